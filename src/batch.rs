@@ -0,0 +1,136 @@
+//! Passing many lines of input to a single command, like xargs does
+
+use crate::error::{Error, Result};
+
+/// How large a batch may get
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// number of items
+    pub args: usize,
+    /// size of the command line, see [`size`]
+    pub chars: usize,
+}
+
+impl Limits {
+    /// Limits of batches, the size of the command line never exceeds what the system allows
+    pub fn new(args: Option<usize>, chars: Option<usize>) -> Result<Self> {
+        if args == Some(0) || chars == Some(0) {
+            return Err(Error::Usage("batches must not be empty".into()));
+        }
+
+        let system = system_limit();
+        Ok(Limits {
+            args: args.unwrap_or(usize::MAX),
+            chars: chars.map_or(system, |chars| chars.min(system)),
+        })
+    }
+}
+
+/// Space `args` take of the system limit, including terminating zeros and pointers
+pub fn size(args: &[String]) -> usize {
+    args.iter()
+        .map(|arg| arg.len() + 1 + std::mem::size_of::<usize>())
+        .sum()
+}
+
+/// Group `items` into batches within `limits`, with every batch taking `base` and its items `size` of the command line.
+/// An item too large for any batch gets a batch on its own, letting the command fail.
+pub fn batches<T>(
+    items: impl Iterator<Item = T>,
+    base: usize,
+    limits: Limits,
+    size: impl Fn(&T) -> usize,
+) -> impl Iterator<Item = Vec<T>> {
+    let mut items = items.peekable();
+
+    std::iter::from_fn(move || {
+        let mut batch = Vec::new();
+        let mut chars = base;
+
+        while batch.len() < limits.args {
+            let item_size = match items.peek() {
+                Some(item) => size(item),
+                None => break,
+            };
+            if !batch.is_empty() && chars + item_size > limits.chars {
+                break;
+            }
+
+            chars += item_size;
+            batch.extend(items.next());
+        }
+
+        (!batch.is_empty()).then_some(batch)
+    })
+}
+
+/// Space left for arguments, after the environment and some headroom like xargs keeps
+#[cfg(unix)]
+fn system_limit() -> usize {
+    // POSIX guarantees at least 4096 bytes
+    // SAFETY: sysconf only reads a system setting, it takes no pointers
+    let max = match unsafe { libc::sysconf(libc::_SC_ARG_MAX) } {
+        max if max > 0 => max as usize,
+        _ => 4096,
+    };
+
+    let environment: usize = std::env::vars_os()
+        .map(|(key, value)| key.len() + value.len() + 2 + std::mem::size_of::<usize>())
+        .sum();
+
+    max.saturating_sub(environment).saturating_sub(2048).max(1)
+}
+
+/// Windows limits command lines to 32767 characters
+#[cfg(not(unix))]
+fn system_limit() -> usize {
+    32767 - 2048
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn collect(items: &[&str], limits: Limits) -> Vec<Vec<String>> {
+        let items = items.iter().map(|s| s.to_string());
+        batches(items, 0, limits, |item| item.len()).collect()
+    }
+
+    #[test]
+    fn max_args() {
+        let limits = Limits::new(Some(2), None).unwrap();
+
+        assert_eq!(
+            collect(&["a", "b", "c"], limits),
+            [vec!["a", "b"], vec!["c"]]
+        );
+        assert!(collect(&[], limits).is_empty());
+    }
+
+    #[test]
+    fn max_chars() {
+        let limits = Limits::new(None, Some(4)).unwrap();
+
+        assert_eq!(
+            collect(&["ab", "cd", "e", "fghij", "k"], limits),
+            [vec!["ab", "cd"], vec!["e"], vec!["fghij"], vec!["k"]]
+        );
+    }
+
+    #[test]
+    fn limits() {
+        assert!(Limits::new(Some(0), None).is_err());
+        assert!(Limits::new(None, Some(0)).is_err());
+        // never more than the system allows
+        assert_eq!(
+            Limits::new(None, Some(usize::MAX)).unwrap().chars,
+            system_limit()
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        let args = ["ab".to_string(), String::new()];
+        assert_eq!(size(&args), 2 + 2 + 2 * std::mem::size_of::<usize>());
+    }
+}
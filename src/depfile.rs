@@ -0,0 +1,84 @@
+use std::{io::Write, path::Path};
+
+/// Write a Makefile style dependency file, declaring that `target` depends on `dependencies`.
+///
+/// Every dependency also gets an empty rule, so that make doesn't fail when one of them gets deleted.
+pub fn write_depfile(path: &Path, target: &str, dependencies: &[&Path]) -> std::io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(&depfile(target, dependencies))?;
+    file.flush()
+}
+
+fn depfile(target: &str, dependencies: &[&Path]) -> Vec<u8> {
+    let dependencies: Vec<String> = dependencies
+        .iter()
+        .map(|path| escape(&path.to_string_lossy()))
+        .collect();
+
+    let mut buffer = format!("{}:", escape(target));
+    for dep in &dependencies {
+        buffer.push(' ');
+        buffer.push_str(dep);
+    }
+    buffer.push('\n');
+
+    for dep in &dependencies {
+        buffer.push_str(&format!("\n{}:\n", dep));
+    }
+
+    buffer.into_bytes()
+}
+
+/// Escape a path the way make expects it in a rule
+fn escape(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            ' ' | '#' | '\\' => escaped.push('\\'),
+            '$' => escaped.push('$'),
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The default target of a dependency file, which is the path of the file without its extension.
+/// e.g. `deployment.yaml.d` yields `deployment.yaml`
+pub fn default_target(path: &Path) -> String {
+    path.with_extension("").to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn single_dependency() {
+        let result = depfile("out.yaml", &[Path::new("in.template.yaml")]);
+        let expected = "out.yaml: in.template.yaml\n\nin.template.yaml:\n";
+
+        assert_eq!(String::from_utf8(result).unwrap(), expected);
+    }
+
+    #[test]
+    fn no_dependencies() {
+        let result = depfile("out.yaml", &[]);
+
+        assert_eq!(String::from_utf8(result).unwrap(), "out.yaml:\n");
+    }
+
+    #[test]
+    fn escaping() {
+        let result = depfile("my out", &[Path::new("$HOME/#1.txt")]);
+        let expected = "my\\ out: $$HOME/\\#1.txt\n\n$$HOME/\\#1.txt:\n";
+
+        assert_eq!(String::from_utf8(result).unwrap(), expected);
+    }
+
+    #[test]
+    fn target_from_path() {
+        assert_eq!(default_target(Path::new("out.yaml.d")), "out.yaml");
+        assert_eq!(default_target(Path::new("build/out.d")), "build/out");
+    }
+}
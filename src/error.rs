@@ -0,0 +1,175 @@
+use std::{fmt, process::ExitStatus, time::Duration};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong while performing a command
+#[derive(Debug)]
+pub enum Error {
+    /// A process could not be started
+    Spawn {
+        command: String,
+        source: std::io::Error,
+    },
+    /// A process terminated unsuccessfully
+    Exit {
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// A process ran for too long and got killed
+    Timeout { command: String, timeout: Duration },
+    /// A process wrote more output than allowed and got killed
+    OutputLimit { command: String, limit: u64 },
+    /// A coprocess didn't answer
+    Coprocess { command: String, message: String },
+    /// Commands failed while keeping going, with where they originate from
+    Failures {
+        failures: Vec<(String, Error)>,
+        total: usize,
+    },
+    /// Input or program output is not valid utf-8
+    InvalidUtf8 { what: String },
+    /// A template could not be parsed
+    Parse {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// A line or character that doesn't exist was requested
+    OutOfRange(String),
+    /// The arguments don't make sense
+    Usage(String),
+    /// Rendering the same template twice yielded different outputs
+    NotReproducible {
+        line: usize,
+        first: String,
+        second: String,
+    },
+    /// Reading input or writing output failed
+    Io(std::io::Error),
+}
+
+impl Error {
+    /// Exit code of `string` when failing with this error
+    ///
+    /// | code | meaning                                                  |
+    /// |------|----------------------------------------------------------|
+    /// | 1    | reading input or writing output failed                   |
+    /// | 2    | invalid arguments                                        |
+    /// | 3    | invalid input, e.g. not utf-8 or not enough lines        |
+    /// | 4    | a command couldn't be started or failed                  |
+    /// | 5    | a template couldn't be parsed                            |
+    /// | 6    | a command timed out                                      |
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 1,
+            Error::Usage(_) => 2,
+            Error::InvalidUtf8 { .. } | Error::OutOfRange(_) => 3,
+            Error::Spawn { .. }
+            | Error::Exit { .. }
+            | Error::OutputLimit { .. }
+            | Error::Coprocess { .. }
+            | Error::Failures { .. }
+            | Error::NotReproducible { .. } => 4,
+            Error::Parse { .. } => 5,
+            Error::Timeout { .. } => 6,
+        }
+    }
+
+    /// Exit code of the failed process, if this error is about one.
+    /// Processes killed by a signal yield 128 + the signal number, like in a shell.
+    pub fn child_exit_code(&self) -> Option<i32> {
+        let status = match self {
+            Error::Exit { status, .. } => status,
+            _ => return None,
+        };
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                return Some(128 + signal);
+            }
+        }
+
+        status.code()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Spawn { command, source } => {
+                write!(f, "failed to spawn process {}: {}", command, source)
+            }
+            Error::Exit {
+                command,
+                status,
+                stderr,
+            } => {
+                write!(
+                    f,
+                    "error executing command {}.\nProcess terminated with {}.",
+                    command, status
+                )?;
+                if !stderr.is_empty() {
+                    write!(f, "\nProgram output:\n{}", stderr)?;
+                }
+                Ok(())
+            }
+            Error::Timeout { command, timeout } => write!(
+                f,
+                "command {} timed out after {:?} and got killed",
+                command, timeout
+            ),
+            Error::OutputLimit { command, limit } => write!(
+                f,
+                "command {} wrote more than {} bytes of output and got killed",
+                command, limit
+            ),
+            Error::Coprocess { command, message } => {
+                write!(f, "coprocess {} {}", command, message)
+            }
+            Error::Failures { failures, total } => {
+                write!(f, "{} of {} commands failed:", failures.len(), total)?;
+                for (origin, error) in failures {
+                    write!(f, "\n{}: {}", origin, error)?;
+                }
+                Ok(())
+            }
+            Error::InvalidUtf8 { what } => write!(f, "{} is not valid utf-8", what),
+            Error::Parse {
+                line,
+                column,
+                message,
+            } => write!(f, "line {} column {}: {}", line, column, message),
+            Error::OutOfRange(message) | Error::Usage(message) => write!(f, "{}", message),
+            Error::NotReproducible {
+                line,
+                first,
+                second,
+            } => write!(
+                f,
+                "render is not reproducible, line {} differs:\n- {}\n+ {}",
+                line, first, second
+            ),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Spawn { source, .. } => Some(source),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -1,61 +1,946 @@
-use std::{io::Write, process::Stdio};
+use std::{
+    ffi::OsStr,
+    fmt,
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    process::{Child, ChildStderr, ChildStdin, Command, ExitStatus, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant, SystemTime},
+};
 
-pub fn execute(text: &str, shell: &[&str]) -> String {
-    let mut command = std::process::Command::new(shell[0])
-        .args(&shell[1..])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect(&format!("failed to spawn process in shell {:?}", shell));
+use crate::{
+    error::{Error, Result},
+    trace::{self, Trace},
+};
 
-    {
-        let stdin = command
-            .stdin
-            .as_mut()
-            .expect("failed to open stdin of command");
-        stdin
-            .write_all(text.as_bytes())
-            .expect("failed to pipe command into shell");
+/// Settings for every process spawned through this module
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// additional environment variables of the process
+    pub env: Vec<(String, String)>,
+    /// how the output of the process is decoded
+    pub decoding: Decoding,
+    /// where the stderr of the process goes
+    pub stderr: Stderr,
+    /// prefix every line of stderr with the origin of the process
+    pub stderr_prefix: bool,
+    /// where in the input the process originates from
+    pub origin: Option<Origin>,
+    /// kill the process if it runs longer than this
+    pub timeout: Option<Duration>,
+    /// resources the process may use
+    pub limits: Limits,
+    /// what to do if the process fails
+    pub retry: Retry,
+    /// where every spawned process gets recorded
+    pub trace: Option<Trace>,
+}
+
+/// How often and when a failing process is run again
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Retry {
+    /// number of retries, 0 means the process runs only once
+    pub attempts: u32,
+    /// delay before the first retry, doubled for every following one up to [`Retry::MAX_DELAY`]
+    pub delay: Duration,
+    /// only retry on these exit codes, any failure is retried if empty
+    pub exit_codes: Vec<i32>,
+}
+
+impl Retry {
+    /// Longest time to wait before a retry
+    pub const MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+
+    /// Time to wait before retry `attempt`, starting at 0
+    fn delay_before(&self, attempt: u32) -> Duration {
+        self.delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(Duration::MAX)
+            .min(Self::MAX_DELAY)
+    }
+
+    fn applies_to(&self, error: &Error) -> bool {
+        match error {
+            Error::Exit { status, .. } if !self.exit_codes.is_empty() => status
+                .code()
+                .is_some_and(|code| self.exit_codes.contains(&code)),
+            Error::Exit { .. } | Error::Timeout { .. } => self.exit_codes.is_empty(),
+            _ => false,
+        }
+    }
+}
+
+/// Resource limits of a process, `None` means unlimited
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// seconds of cpu time
+    pub cpu: Option<u64>,
+    /// bytes of address space
+    pub memory: Option<u64>,
+    /// number of open files
+    pub files: Option<u64>,
+    /// number of processes of the user, not just the ones spawned by this process
+    pub processes: Option<u64>,
+    /// bytes of output and of captured stderr each, the process gets killed when writing more
+    pub output: Option<u64>,
+}
+
+impl Limits {
+    /// Apply the limits to the current process, meant to be called between fork and exec
+    #[cfg(unix)]
+    fn apply(&self) -> std::io::Result<()> {
+        let set = |resource, limit: Option<u64>| {
+            let Some(limit) = limit else {
+                return Ok(());
+            };
+            let limit = libc::rlimit {
+                rlim_cur: limit as libc::rlim_t,
+                rlim_max: limit as libc::rlim_t,
+            };
+
+            // SAFETY: setrlimit only reads the given struct
+            match unsafe { libc::setrlimit(resource, &limit) } {
+                0 => Ok(()),
+                _ => Err(std::io::Error::last_os_error()),
+            }
+        };
+
+        set(libc::RLIMIT_CPU, self.cpu)?;
+        set(libc::RLIMIT_AS, self.memory)?;
+        set(libc::RLIMIT_NOFILE, self.files)?;
+        set(libc::RLIMIT_NPROC, self.processes)
+    }
+}
+
+/// Where in the input a process originates from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// a command of a template, line and column start at 1
+    Block {
+        index: usize,
+        line: usize,
+        column: usize,
+    },
+    /// a line of input, starting at 1
+    Line(usize),
+    /// several lines of input, starting at 1
+    Lines { first: usize, last: usize },
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Block {
+                index,
+                line,
+                column,
+            } => write!(f, "block {} at {}:{}", index, line, column),
+            Origin::Line(line) => write!(f, "line {}", line),
+            Origin::Lines { first, last } => write!(f, "lines {}-{}", first, last),
+        }
+    }
+}
+
+/// Where the stderr of a process goes
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum Stderr {
+    /// the stderr of `string`
+    Inherit,
+    /// keep it and show it when the process fails
+    #[default]
+    Capture,
+    /// mix it into the output of the process
+    Merge,
+    /// throw it away
+    Discard,
+    /// append it to a file
+    File(PathBuf),
+}
+
+impl std::str::FromStr for Stderr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "inherit" => Ok(Stderr::Inherit),
+            "capture" => Ok(Stderr::Capture),
+            "merge" => Ok(Stderr::Merge),
+            "discard" => Ok(Stderr::Discard),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(Stderr::File(path.into())),
+                _ => Err(format!(
+                    "unknown stderr mode `{}`, expected inherit, capture, merge, discard or file:PATH",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// What to do with program output that is not valid utf-8
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Decoding {
+    /// fail
+    #[default]
+    Strict,
+    /// replace invalid sequences with U+FFFD
+    Lossy,
+    /// pass the bytes through unchanged
+    Raw,
+}
+
+impl std::str::FromStr for Decoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Decoding::Strict),
+            "lossy" => Ok(Decoding::Lossy),
+            "raw" => Ok(Decoding::Raw),
+            _ => Err(format!(
+                "unknown decoding `{}`, expected strict, lossy or raw",
+                s
+            )),
+        }
+    }
+}
+
+impl Decoding {
+    fn decode(self, output: Vec<u8>, description: &str) -> Result<Vec<u8>> {
+        match self {
+            Decoding::Strict => match String::from_utf8(output) {
+                Ok(s) => Ok(s.into_bytes()),
+                Err(_) => Err(Error::InvalidUtf8 {
+                    what: format!("output of {}", description),
+                }),
+            },
+            Decoding::Lossy => Ok(String::from_utf8_lossy(&output).into_owned().into_bytes()),
+            Decoding::Raw => Ok(output),
+        }
+    }
+}
+
+impl Options {
+    /// Pin locale, timezone and timestamp, so that commands behave the same on every machine.
+    ///
+    /// An existing `SOURCE_DATE_EPOCH` is honoured, otherwise it is set to 0.
+    pub fn reproducible(mut self) -> Result<Self> {
+        let epoch = match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(epoch) if epoch.parse::<u64>().is_ok() => epoch,
+            Ok(epoch) => {
+                return Err(Error::Usage(format!(
+                    "SOURCE_DATE_EPOCH must be a unix timestamp, got `{}`",
+                    epoch
+                )))
+            }
+            Err(_) => "0".to_string(),
+        };
+
+        let env = [
+            ("LANG", "C"),
+            ("LC_ALL", "C"),
+            ("TZ", "UTC"),
+            ("SOURCE_DATE_EPOCH", &epoch),
+        ];
+
+        self.env
+            .extend(env.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+
+        Ok(self)
+    }
+}
+
+pub fn execute(text: &str, shell: &[&str], options: &Options) -> Result<Vec<u8>> {
+    if shell.is_empty() {
+        return Err(Error::Usage("must specify a shell".into()));
+    }
+
+    run(&Invocation::Shell { text, shell }, options)
+}
+
+pub fn execute_command(command: &[String], options: &Options) -> Result<Vec<u8>> {
+    if command.is_empty() {
+        return Err(Error::Usage("must specify a command".into()));
+    }
+
+    run(&Invocation::Command(command), options)
+}
+
+/// Shell command line running `command` with `input` on stdin, for showing commands instead of running them
+pub fn shell_line(input: Option<&str>, command: &[impl AsRef<str>]) -> String {
+    let command = shell_words::join(command);
+
+    match input {
+        Some(input) => format!("printf %s {} | {}", shell_words::quote(input), command),
+        None => command,
+    }
+}
+
+/// A process to spawn
+enum Invocation<'a> {
+    /// `text` piped into `shell`
+    Shell { text: &'a str, shell: &'a [&'a str] },
+    /// a program and its arguments
+    Command(&'a [String]),
+}
+
+impl Invocation<'_> {
+    fn command(&self) -> Command {
+        match self {
+            Invocation::Shell { shell, .. } => new_command(shell),
+            Invocation::Command(argv) => new_command(argv),
+        }
+    }
+
+    fn input(&self) -> Option<&[u8]> {
+        match self {
+            Invocation::Shell { text, .. } => Some(text.as_bytes()),
+            Invocation::Command(_) => None,
+        }
+    }
+
+    fn trace(&self) -> (String, Option<&[&str]>) {
+        match self {
+            Invocation::Shell { text, shell } => (text.to_string(), Some(shell)),
+            Invocation::Command(argv) => (shell_words::join(argv.iter()), None),
+        }
+    }
+}
+
+impl fmt::Display for Invocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Invocation::Shell { text, shell } => write!(f, "`{}` in shell {}", text, shell[0]),
+            Invocation::Command(argv) => write!(f, "`{:?}`", argv),
+        }
     }
+}
+
+/// Command running `argv[0]` with the remaining arguments, `argv` must not be empty
+fn new_command(argv: &[impl AsRef<OsStr>]) -> Command {
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]);
+    command
+}
+
+/// What is known about a finished process
+#[derive(Debug, Default)]
+struct Report {
+    status: Option<ExitStatus>,
+    /// bytes of output
+    stdout: Option<usize>,
+    /// bytes of stderr, if it went through `string`
+    stderr: Option<usize>,
+}
+
+/// Run a program to completion, retrying as often as the options allow
+fn run(invocation: &Invocation, options: &Options) -> Result<Vec<u8>> {
+    let description = invocation.to_string();
+    let retry = &options.retry;
+    let mut attempt = 0;
+
+    loop {
+        let start = SystemTime::now();
+        let timer = Instant::now();
+        let mut report = Report::default();
+
+        let result = run_once(invocation, &description, options, &mut report);
+
+        if let Some(trace) = &options.trace {
+            let (command, shell) = invocation.trace();
+            trace.record(&trace::Record {
+                command: &command,
+                shell,
+                origin: options.origin,
+                attempt: attempt + 1,
+                start,
+                duration: timer.elapsed(),
+                status: report.status,
+                stdout: report.stdout,
+                stderr: report.stderr,
+                error: match &result {
+                    Ok(_) | Err(Error::Exit { .. }) => None,
+                    Err(e) => Some(e.to_string()),
+                },
+            })?;
+        }
+
+        let error = match result {
+            Err(e) if attempt < retry.attempts && retry.applies_to(&e) => e,
+            result => return result,
+        };
+
+        let delay = retry.delay_before(attempt);
+        attempt += 1;
+
+        let origin = options
+            .origin
+            .map(|o| format!(" ({})", o))
+            .unwrap_or_default();
+        let reason = match error {
+            Error::Exit { status, .. } => format!("terminated with {}", status),
+            _ => "timed out".to_string(),
+        };
+        eprintln!(
+            "command {}{} {}, retrying in {:?} ({}/{})",
+            description, origin, reason, delay, attempt, retry.attempts
+        );
+        std::thread::sleep(delay);
+    }
+}
+
+/// Run a process to completion and return its output
+fn run_once(
+    invocation: &Invocation,
+    description: &str,
+    options: &Options,
+    report: &mut Report,
+) -> Result<Vec<u8>> {
+    let input = invocation.input();
+    let Spawned {
+        mut child,
+        stdin,
+        stdout,
+        stderr,
+    } = spawn(invocation.command(), description, options)?;
 
-    let output = command
-        .wait_with_output()
-        .expect("failed to aquire programm output");
+    // input is written while output is read, otherwise a process filling up
+    // its output pipe before reading all of its input would never finish.
+    let limit = options.limits.output.map(OutputLimit::new);
+    let limit = limit.as_ref();
+    let (status, stdout, stderr) = std::thread::scope(|scope| {
+        let writer = scope.spawn(move || write_input(stdin, input));
+        let stdout = scope.spawn(move || read_all(stdout, limit));
+        let stderr = stderr.map(|stderr| scope.spawn(move || stderr.forward(limit)));
+
+        // the process is only killed here, before it is waited for, so that its id can't be reused yet
+        let status = match (options.timeout, limit) {
+            (None, None) => child.wait().map_err(Error::from),
+            (timeout, limit) => {
+                wait_killable(&mut child, timeout, limit).ok_or_else(|| Error::Timeout {
+                    command: description.to_string(),
+                    // without a timeout the process is only killed for its output,
+                    // which gets reported instead
+                    timeout: timeout.unwrap_or_default(),
+                })
+            }
+        };
+        report.status = status.as_ref().ok().copied();
+
+        writer.join().expect("stdin thread panicked")?;
+        let stdout = stdout.join().expect("stdout thread panicked")?;
+        let stderr = match stderr {
+            Some(thread) => {
+                let (captured, size) = thread.join().expect("stderr thread panicked")?;
+                report.stderr = Some(size);
+                captured
+            }
+            None => Vec::new(),
+        };
+
+        if let Some(limit) = limit.filter(|limit| limit.exceeded()) {
+            return Err(Error::OutputLimit {
+                command: description.to_string(),
+                limit: limit.bytes,
+            });
+        }
+        report.stdout = Some(stdout.len());
+
+        Ok::<_, Error>((status?, stdout, stderr))
+    })?;
 
-    let status: std::process::ExitStatus = output.status;
     if !status.success() {
-        eprintln!("error executing command `{}` in shell {}.\nProcess terminated with exit code {}.\nProgram output:\n{}", text, shell[0], status, String::from_utf8(output.stderr).unwrap());
-        std::process::exit(1);
+        return Err(Error::Exit {
+            command: description.to_string(),
+            status,
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        });
     }
 
-    String::from_utf8(output.stdout).expect("programm output was not valid utf-8")
+    options.decoding.decode(stdout, description)
+}
+
+/// A freshly spawned process and the pipes to talk to it
+struct Spawned {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Box<dyn Read + Send>,
+    /// the stderr pipe, if stderr goes through `string`
+    stderr: Option<StderrPipe>,
+}
+
+struct StderrPipe {
+    pipe: ChildStderr,
+    prefix: Option<String>,
+    /// where lines are written to, they are captured if there is none
+    sink: Option<Box<dyn Write + Send>>,
 }
 
-pub fn execute_command(command: &[String]) -> String {
-    let cmd = std::process::Command::new(&command[0])
-        .args(&command[1..])
+impl StderrPipe {
+    /// Forward the lines of the pipe, only captured lines count towards the output limit
+    fn forward(self, limit: Option<&OutputLimit>) -> Forwarded {
+        let limit = limit.filter(|_| self.sink.is_none());
+        forward(self.pipe, self.prefix, self.sink, limit)
+    }
+}
+
+/// Limit of the output of a process, shared by the threads reading it
+struct OutputLimit {
+    bytes: u64,
+    exceeded: AtomicBool,
+}
+
+impl OutputLimit {
+    fn new(bytes: u64) -> Self {
+        OutputLimit {
+            bytes,
+            exceeded: AtomicBool::new(false),
+        }
+    }
+
+    fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+
+    /// Mark the limit exceeded if `size` bytes are more than it allows, returns whether they are
+    fn check(&self, size: usize) -> bool {
+        let exceeded = size as u64 > self.bytes;
+        if exceeded {
+            self.exceeded.store(true, Ordering::Relaxed);
+        }
+        exceeded
+    }
+}
+
+/// Spawn a command with stdin and stdout piped and stderr set up as the options say
+fn spawn(mut command: Command, description: &str, options: &Options) -> Result<Spawned> {
+    let prefix = match options.origin {
+        Some(origin) if options.stderr_prefix => Some(format!("[{}] ", origin)),
+        _ => None,
+    };
+
+    // where forwarded lines of stderr are written to, if they aren't captured
+    let mut sink: Option<Box<dyn Write + Send>> = None;
+    let mut merged = None;
+
+    let stderr = match &options.stderr {
+        Stderr::Capture => Stdio::piped(),
+        Stderr::Discard => Stdio::null(),
+        Stderr::Inherit if prefix.is_none() => Stdio::inherit(),
+        Stderr::Inherit => {
+            sink = Some(Box::new(std::io::stderr()));
+            Stdio::piped()
+        }
+        Stderr::File(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            if prefix.is_none() {
+                Stdio::from(file)
+            } else {
+                sink = Some(Box::new(file));
+                Stdio::piped()
+            }
+        }
+        Stderr::Merge => {
+            let (reader, writer) = std::io::pipe()?;
+            command.stdout(writer.try_clone()?);
+            merged = Some(reader);
+            Stdio::from(writer)
+        }
+    };
+
+    if merged.is_none() {
+        command.stdout(Stdio::piped());
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+
+        // processes that may get killed are spawned into their own process group,
+        // so that everything they spawn can be killed along with them
+        if options.timeout.is_some() || options.limits.output.is_some() {
+            command.process_group(0);
+        }
+
+        if options.limits != Limits::default() {
+            let limits = options.limits;
+            // SAFETY: setting resource limits is async-signal-safe and doesn't allocate
+            unsafe {
+                command.pre_exec(move || limits.apply());
+            }
+        }
+    }
+
+    let mut child = command
+        .envs(options.env.iter().map(|(k, v)| (k, v)))
         .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
+        .stderr(stderr)
         .spawn()
-        .expect(&format!("failed to spawn process in shell {:?}", &command));
+        .map_err(|source| Error::Spawn {
+            command: description.to_string(),
+            source,
+        })?;
 
-    let output = cmd
-        .wait_with_output()
-        .expect("failed to aquire programm output");
+    // the command still holds the writing end of a merged pipe,
+    // which would keep the pipe from ever reaching its end
+    drop(command);
 
-    let status: std::process::ExitStatus = output.status;
-    if !status.success() {
-        eprintln!(
-            "error executing command `{:?
-            }`.\nProcess terminated with exit code {}.\nProgram output:\n{}",
-            command,
-            status,
-            String::from_utf8(output.stderr).unwrap()
-        );
-        std::process::exit(1);
+    // unwrapping is safe, stdin and stdout are piped
+    let stdin = child.stdin.take().unwrap();
+    let stdout: Box<dyn Read + Send> = match merged {
+        Some(pipe) => Box::new(pipe),
+        None => Box::new(child.stdout.take().unwrap()),
+    };
+    let stderr = child
+        .stderr
+        .take()
+        .map(|pipe| StderrPipe { pipe, prefix, sink });
+
+    Ok(Spawned {
+        child,
+        stdin,
+        stdout,
+        stderr,
+    })
+}
+
+/// How a coprocess marks the end of each answer
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum Delimiter {
+    #[default]
+    Newline,
+    Nul,
+    /// a line containing only this text
+    Sentinel(String),
+}
+
+impl std::str::FromStr for Delimiter {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "newline" => Ok(Delimiter::Newline),
+            "nul" => Ok(Delimiter::Nul),
+            "" => Err("delimiter must not be empty".into()),
+            _ => Ok(Delimiter::Sentinel(s.to_string())),
+        }
     }
+}
 
-    String::from_utf8(output.stdout).expect("programm output was not valid utf-8")
+/// Captured stderr of a process and its total size, see [`forward`]
+type Forwarded = std::io::Result<(Vec<u8>, usize)>;
+
+/// A long running process, receiving one line of input at a time on stdin
+/// and answering each of them on stdout.
+///
+/// Timeouts, retries and output limits don't apply to coprocesses.
+pub struct Coprocess {
+    child: Child,
+    /// records to write to stdin, written on a thread of their own so large ones can't block reading answers
+    stdin: Option<std::sync::mpsc::Sender<Vec<u8>>>,
+    writer: Option<std::thread::JoinHandle<std::io::Result<()>>>,
+    stdout: BufReader<Box<dyn Read + Send>>,
+    stderr: Option<std::thread::JoinHandle<Forwarded>>,
+    delimiter: Delimiter,
+    decoding: Decoding,
+    description: String,
+}
+
+impl Coprocess {
+    pub fn start(command: &[String], delimiter: Delimiter, options: &Options) -> Result<Self> {
+        if command.is_empty() {
+            return Err(Error::Usage("must specify a command".into()));
+        }
+        if options.stderr == Stderr::Merge {
+            return Err(Error::Usage(
+                "stderr of a coprocess can't be merged into its answers".into(),
+            ));
+        }
+        let unsupported = [
+            ("--timeout", options.timeout.is_some()),
+            ("--retries", options.retry.attempts > 0),
+            ("--limit-output", options.limits.output.is_some()),
+            ("--trace", options.trace.is_some()),
+        ];
+        if let Some((option, _)) = unsupported.iter().find(|(_, given)| *given) {
+            return Err(Error::Usage(format!(
+                "{} doesn't apply to a coprocess",
+                option
+            )));
+        }
+
+        let description = Invocation::Command(command).to_string();
+        let Spawned {
+            child,
+            stdin,
+            stdout,
+            stderr,
+        } = spawn(new_command(command), &description, options)?;
+
+        let (sender, records) = std::sync::mpsc::channel::<Vec<u8>>();
+        let writer = std::thread::spawn(move || {
+            let mut stdin = stdin;
+            for record in records {
+                match stdin.write_all(&record) {
+                    // the process stopped reading, which shows when reading its answer
+                    Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => break,
+                    result => result?,
+                }
+            }
+            Ok(())
+        });
+
+        Ok(Coprocess {
+            child,
+            stdin: Some(sender),
+            writer: Some(writer),
+            stdout: BufReader::new(stdout),
+            stderr: stderr.map(|stderr| std::thread::spawn(move || stderr.forward(None))),
+            delimiter,
+            decoding: options.decoding,
+            description,
+        })
+    }
+
+    /// Send a line to the process and return its answer, without the delimiter
+    pub fn call(&mut self, line: &str) -> Result<Vec<u8>> {
+        // the process reads lines, a newline inside would shift every later answer
+        if line.contains('\n') {
+            return Err(Error::Coprocess {
+                command: self.description.clone(),
+                message: format!(
+                    "can't send `{}`, it contains a newline",
+                    line.escape_debug()
+                ),
+            });
+        }
+
+        let mut record = Vec::with_capacity(line.len() + 1);
+        record.extend_from_slice(line.as_bytes());
+        record.push(b'\n');
+
+        // unwrapping is safe, stdin is only taken when finishing
+        let answer = match self.stdin.as_ref().unwrap().send(record) {
+            Ok(()) => self.read_answer()?,
+            // the writer stopped, because the process stopped reading
+            Err(_) => None,
+        };
+
+        match answer {
+            Some(answer) => self.decoding.decode(answer, &self.description),
+            None => {
+                self.finish_process()?;
+                Err(Error::Coprocess {
+                    command: self.description.clone(),
+                    message: format!("stopped before answering `{}`", line),
+                })
+            }
+        }
+    }
+
+    /// Read the next answer, returns `None` if the process closed its output before
+    fn read_answer(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut answer = Vec::new();
+
+        match &self.delimiter {
+            Delimiter::Newline | Delimiter::Nul => {
+                let delimiter = match self.delimiter {
+                    Delimiter::Nul => 0,
+                    _ => b'\n',
+                };
+
+                self.stdout.read_until(delimiter, &mut answer)?;
+                if answer.pop() != Some(delimiter) {
+                    return Ok(None);
+                }
+            }
+            Delimiter::Sentinel(sentinel) => {
+                let mut line = Vec::new();
+                loop {
+                    line.clear();
+                    if self.stdout.read_until(b'\n', &mut line)? == 0 {
+                        return Ok(None);
+                    }
+
+                    if line.strip_suffix(b"\n").unwrap_or(&line) == sentinel.as_bytes() {
+                        break;
+                    }
+                    answer.extend_from_slice(&line);
+                }
+
+                if answer.ends_with(b"\n") {
+                    answer.pop();
+                }
+            }
+        }
+
+        Ok(Some(answer))
+    }
+
+    /// Close the input of the process and wait for it to exit
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_process()
+    }
+
+    fn finish_process(&mut self) -> Result<()> {
+        drop(self.stdin.take());
+
+        // whatever the process still has to say isn't an answer to anything
+        std::io::copy(&mut self.stdout, &mut std::io::sink())?;
+        let status = self.child.wait()?;
+
+        if let Some(writer) = self.writer.take() {
+            writer.join().expect("stdin thread panicked")?;
+        }
+
+        let stderr = match self.stderr.take() {
+            Some(thread) => thread.join().expect("stderr thread panicked")?.0,
+            None => Vec::new(),
+        };
+
+        if !status.success() {
+            return Err(Error::Exit {
+                command: self.description.clone(),
+                status,
+                stderr: String::from_utf8_lossy(&stderr).into_owned(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Write `input` to the stdin of a process and close it
+fn write_input(mut stdin: ChildStdin, input: Option<&[u8]>) -> std::io::Result<()> {
+    if let Some(input) = input {
+        match stdin.write_all(input) {
+            // the process doesn't care about its input, that's fine
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+            result => result?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Read all output of a process.
+/// Stops reading and marks the limit exceeded, if it writes more than the limit allows.
+fn read_all(pipe: impl Read, limit: Option<&OutputLimit>) -> std::io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+
+    let Some(limit) = limit else {
+        let mut pipe = pipe;
+        pipe.read_to_end(&mut buffer)?;
+        return Ok(buffer);
+    };
+
+    pipe.take(limit.bytes + 1).read_to_end(&mut buffer)?;
+    limit.check(buffer.len());
+
+    Ok(buffer)
+}
+
+/// Wait for a process to exit, kill it and all of its children once `timeout` elapsed
+/// or its output exceeded `limit`.
+/// Returns `None` if the process got killed.
+fn wait_killable(
+    child: &mut Child,
+    timeout: Option<Duration>,
+    limit: Option<&OutputLimit>,
+) -> Option<ExitStatus> {
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Some(status),
+            Ok(None) => {}
+            Err(_) => break,
+        }
+
+        if limit.is_some_and(OutputLimit::exceeded) {
+            break;
+        }
+        let now = Instant::now();
+        let pause = Duration::from_millis(10);
+        match deadline {
+            Some(deadline) if now >= deadline => break,
+            Some(deadline) => std::thread::sleep((deadline - now).min(pause)),
+            None => std::thread::sleep(pause),
+        }
+    }
+
+    kill_group(child.id());
+    let _ = child.wait();
+    None
+}
+
+/// Kill a process spawned into its own process group, together with everything it spawned.
+/// The process must not have been waited for yet, otherwise its id might be reused.
+#[cfg(unix)]
+fn kill_group(pid: u32) {
+    // SAFETY: kill has no memory safety requirements
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_group(pid: u32) {
+    let _ = Command::new("taskkill")
+        .args(["/F", "/T", "/PID", &pid.to_string()])
+        .status();
+}
+
+/// Read the stderr of a process line by line, prefixing each line.
+/// Lines are written to `sink` or returned, if there is none.
+/// Also returns the number of bytes read.
+/// Stops reading and marks the limit exceeded, once more than `limit` bytes were read.
+fn forward(
+    pipe: impl Read,
+    prefix: Option<String>,
+    mut sink: Option<Box<dyn Write + Send>>,
+    limit: Option<&OutputLimit>,
+) -> std::io::Result<(Vec<u8>, usize)> {
+    let mut captured = Vec::new();
+    let mut size = 0;
+    let mut pipe = BufReader::new(pipe);
+    let mut line = Vec::new();
+
+    loop {
+        let read = match limit {
+            // one byte more than allowed shows that the limit is exceeded
+            Some(limit) => {
+                let left = limit.bytes.saturating_sub(size as u64);
+                (&mut pipe).take(left + 1).read_until(b'\n', &mut line)?
+            }
+            None => pipe.read_until(b'\n', &mut line)?,
+        };
+        if read == 0 {
+            break;
+        }
+        size += line.len();
+        if limit.is_some_and(|limit| limit.check(size)) {
+            break;
+        }
+
+        if !line.ends_with(b"\n") {
+            line.push(b'\n');
+        }
+        if let Some(prefix) = &prefix {
+            line.splice(0..0, prefix.bytes());
+        }
+
+        // the line is written at once, so that lines of processes running in parallel don't mix
+        match &mut sink {
+            Some(sink) => sink.write_all(&line)?,
+            None => captured.extend_from_slice(&line),
+        }
+
+        line.clear();
+    }
+
+    Ok((captured, size))
 }
 
 #[cfg(test)]
@@ -65,9 +950,328 @@ mod test {
     #[test]
     fn exec1() {
         let input = "printf hello";
-        let result = execute(input, &["sh"]);
-        let expected = "hello";
+        let result = execute(input, &["sh"], &Options::default()).unwrap();
+        let expected = b"hello";
+
+        assert_eq!(expected, &result[..]);
+    }
+
+    #[test]
+    fn exit_status() {
+        let result = execute("echo oops >&2; exit 3", &["sh"], &Options::default());
+
+        match result {
+            Err(Error::Exit { status, stderr, .. }) => {
+                assert_eq!(status.code(), Some(3));
+                assert_eq!(stderr, "oops\n");
+            }
+            other => panic!("expected exit error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stderr_modes() {
+        let text = "echo out; echo err >&2; echo more";
+        let origin = Some(Origin::Line(7));
+
+        let merged = Options {
+            stderr: Stderr::Merge,
+            ..Options::default()
+        };
+        let result = execute(text, &["sh"], &merged).unwrap();
+        assert_eq!(result, b"out\nerr\nmore\n");
+
+        let discard = Options {
+            stderr: Stderr::Discard,
+            ..Options::default()
+        };
+        let result = execute(text, &["sh"], &discard).unwrap();
+        assert_eq!(result, b"out\nmore\n");
+
+        let path = std::env::temp_dir().join(format!("string-stderr-{}", std::process::id()));
+        let file = Options {
+            stderr: Stderr::File(path.clone()),
+            stderr_prefix: true,
+            origin,
+            ..Options::default()
+        };
+        execute(text, &["sh"], &file).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, "[line 7] err\n");
+
+        let capture = Options {
+            stderr_prefix: true,
+            origin,
+            ..Options::default()
+        };
+        match execute("echo a >&2; printf b >&2; exit 1", &["sh"], &capture) {
+            Err(Error::Exit { stderr, .. }) => assert_eq!(stderr, "[line 7] a\n[line 7] b\n"),
+            other => panic!("expected exit error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn large_input_and_output() {
+        let input = "x".repeat(8 * 1024 * 1024);
+        let options = Options {
+            stderr: Stderr::Merge,
+            ..Options::default()
+        };
+
+        let result = execute(&input, &["sh", "-c", "tee /dev/stderr"], &options).unwrap();
+        assert_eq!(result.len(), 2 * input.len());
+    }
+
+    #[test]
+    fn timeout() {
+        let options = Options {
+            timeout: Some(Duration::from_millis(100)),
+            ..Options::default()
+        };
+
+        let start = Instant::now();
+        let result = execute("sleep 5 | cat", &["sh"], &options);
+
+        assert!(matches!(result, Err(Error::Timeout { .. })));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let result = execute("echo fast", &["sh"], &options).unwrap();
+        assert_eq!(result, b"fast\n");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn limits() {
+        let options = Options {
+            limits: Limits {
+                cpu: Some(7),
+                memory: Some(1 << 30),
+                files: Some(64),
+                processes: Some(1000),
+                output: None,
+            },
+            ..Options::default()
+        };
+
+        let result = execute("cat /proc/self/limits", &["sh"], &options).unwrap();
+        let result = String::from_utf8(result).unwrap();
+        let limit = |name: &str| {
+            let line = result.lines().find(|l| l.starts_with(name)).unwrap();
+            line[name.len()..]
+                .split_whitespace()
+                .next()
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(limit("Max cpu time"), "7");
+        assert_eq!(limit("Max address space"), (1u64 << 30).to_string());
+        assert_eq!(limit("Max open files"), "64");
+        assert_eq!(limit("Max processes"), "1000");
+    }
+
+    #[test]
+    fn output_limit() {
+        let options = Options {
+            limits: Limits {
+                output: Some(1000),
+                ..Limits::default()
+            },
+            ..Options::default()
+        };
+
+        let result = execute("yes", &["sh"], &options);
+        assert!(matches!(
+            result,
+            Err(Error::OutputLimit { limit: 1000, .. })
+        ));
+
+        let result = execute("printf 1234", &["sh"], &options).unwrap();
+        assert_eq!(result, b"1234");
+
+        // captured stderr is limited as well
+        let result = execute("yes >&2", &["sh"], &options);
+        assert!(matches!(
+            result,
+            Err(Error::OutputLimit { limit: 1000, .. })
+        ));
+    }
+
+    #[test]
+    fn retry() {
+        let marker = std::env::temp_dir().join(format!("string-retry-{}", std::process::id()));
+        let text = format!(
+            "if [ -e {0} ]; then rm {0}; echo done; else touch {0}; exit 3; fi",
+            marker.display()
+        );
+
+        let retry = |exit_codes| Options {
+            retry: Retry {
+                attempts: 2,
+                delay: Duration::from_millis(1),
+                exit_codes,
+            },
+            ..Options::default()
+        };
+
+        let result = execute(&text, &["sh"], &retry(vec![])).unwrap();
+        assert_eq!(result, b"done\n");
+
+        let result = execute(&text, &["sh"], &retry(vec![3])).unwrap();
+        assert_eq!(result, b"done\n");
+
+        let result = execute(&text, &["sh"], &retry(vec![1, 2]));
+        assert!(matches!(result, Err(Error::Exit { .. })));
+        std::fs::remove_file(&marker).unwrap();
+
+        let result = execute("exit 1", &["sh"], &retry(vec![]));
+        assert!(matches!(result, Err(Error::Exit { .. })));
+    }
+
+    #[test]
+    fn retry_delay() {
+        let retry = Retry {
+            attempts: 40,
+            delay: Duration::from_secs(1),
+            exit_codes: vec![],
+        };
+
+        assert_eq!(retry.delay_before(0), Duration::from_secs(1));
+        assert_eq!(retry.delay_before(3), Duration::from_secs(8));
+        assert_eq!(retry.delay_before(39), Retry::MAX_DELAY);
+
+        let retry = Retry {
+            delay: Duration::from_secs(1_000_000_000),
+            ..retry
+        };
+        assert_eq!(retry.delay_before(0), Retry::MAX_DELAY);
+    }
+
+    #[test]
+    fn coprocess() {
+        let cases = [
+            (
+                Delimiter::Newline,
+                r#"while read l; do echo "$l$l"; done"#,
+                ["aa", "bb"],
+            ),
+            (
+                Delimiter::Nul,
+                r#"while read l; do printf '%s\n\0' $l; done"#,
+                ["a\n", "b\n"],
+            ),
+            (
+                Delimiter::Sentinel("END".into()),
+                r#"while read l; do echo $l; echo $l; echo END; done"#,
+                ["a\na", "b\nb"],
+            ),
+        ];
+
+        for (delimiter, script, expected) in cases {
+            let command = ["sh".to_string(), "-c".to_string(), script.to_string()];
+            let mut worker = Coprocess::start(&command, delimiter, &Options::default()).unwrap();
+
+            assert_eq!(worker.call("a").unwrap(), expected[0].as_bytes());
+            assert_eq!(worker.call("b").unwrap(), expected[1].as_bytes());
+            worker.finish().unwrap();
+        }
+    }
+
+    #[test]
+    fn coprocess_large_record() {
+        // larger than any pipe buffer, cat answers while the record is still being written
+        let line = "x".repeat(1 << 20);
+        let command = ["cat".to_string()];
+        let mut worker =
+            Coprocess::start(&command, Delimiter::Newline, &Options::default()).unwrap();
+
+        assert_eq!(worker.call(&line).unwrap(), line.as_bytes());
+        worker.finish().unwrap();
+    }
+
+    #[test]
+    fn coprocess_stops_early() {
+        let command = [
+            "sh".to_string(),
+            "-c".to_string(),
+            "read l; echo $l".to_string(),
+        ];
+        let mut worker =
+            Coprocess::start(&command, Delimiter::Newline, &Options::default()).unwrap();
+
+        assert_eq!(worker.call("a").unwrap(), b"a");
+        assert!(matches!(worker.call("b"), Err(Error::Coprocess { .. })));
+
+        let command = ["cat".to_string()];
+        let mut worker =
+            Coprocess::start(&command, Delimiter::Newline, &Options::default()).unwrap();
+        assert!(matches!(worker.call("a\nb"), Err(Error::Coprocess { .. })));
+
+        let command = [
+            "sh".to_string(),
+            "-c".to_string(),
+            "read l; exit 3".to_string(),
+        ];
+        let mut worker =
+            Coprocess::start(&command, Delimiter::Newline, &Options::default()).unwrap();
+        assert!(matches!(worker.call("a"), Err(Error::Exit { .. })));
+    }
+
+    #[test]
+    fn shell_lines() {
+        assert_eq!(shell_line(None, &["rm", "-f", "a b"]), "rm -f 'a b'");
+        assert_eq!(
+            shell_line(Some("it's"), &["tr", "a-z", "A-Z"]),
+            r#"printf %s 'it'\''s' | tr a-z A-Z"#
+        );
+    }
+
+    #[test]
+    fn stderr_mode_parsing() {
+        assert_eq!("merge".parse(), Ok(Stderr::Merge));
+        assert_eq!("file:err.log".parse(), Ok(Stderr::File("err.log".into())));
+        assert!("file:".parse::<Stderr>().is_err());
+        assert!("stdout".parse::<Stderr>().is_err());
+    }
+
+    #[test]
+    fn spawn_failure() {
+        let command = ["this-program-does-not-exist".to_string()];
+        let result = execute_command(&command, &Options::default());
+
+        assert!(matches!(result, Err(Error::Spawn { .. })));
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let result = execute("printf '\\377'", &["sh"], &Options::default());
+
+        assert!(matches!(result, Err(Error::InvalidUtf8 { .. })));
+    }
+
+    #[test]
+    fn decoding() {
+        let cases = [
+            (Decoding::Lossy, "a\u{FFFD}b".as_bytes()),
+            (Decoding::Raw, b"a\xffb"),
+        ];
+
+        for (decoding, expected) in cases {
+            let options = Options {
+                decoding,
+                ..Options::default()
+            };
+            let result = execute("printf 'a\\377b'", &["sh"], &options).unwrap();
+
+            assert_eq!(result, expected);
+        }
+    }
+
+    #[test]
+    fn no_shell() {
+        let result = execute("echo", &[], &Options::default());
 
-        assert_eq!(expected, result);
+        assert!(matches!(result, Err(Error::Usage(_))));
     }
 }
@@ -0,0 +1,323 @@
+//! Running a command for many lines of input concurrently
+
+use crate::{
+    error::{Error, Result},
+    progress::Progress,
+};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Condvar, Mutex,
+    },
+};
+
+/// With ordered results, items are started at most this many times the number of jobs
+/// ahead of the next result to emit, so that the results held back stay bounded
+const WINDOW: usize = 4;
+
+pub struct Settings {
+    /// how many items are worked on at the same time
+    pub jobs: usize,
+    /// emit results in the order of their items instead of as they complete
+    pub ordered: bool,
+    /// draw the progress on stderr, with an ETA if the number of items is known
+    pub progress: bool,
+}
+
+/// Call `work` for every item on up to `settings.jobs` threads and pass the results to `emit`.
+///
+/// Items are taken from `items` on a thread of their own once a thread is free to work on them,
+/// so they may be read from the input while earlier results are written.
+/// After the first error no more items are started, the error is returned once the running ones are done.
+/// With ordered results, every result before the failing item is emitted first,
+/// and no item is started while the result of an item `jobs * WINDOW` before it is still held back.
+pub fn run<I, R>(
+    items: I,
+    settings: &Settings,
+    work: impl Fn(usize, I::Item) -> Result<R> + Sync,
+    mut emit: impl FnMut(R) -> Result<()>,
+) -> Result<()>
+where
+    I: Iterator + Send,
+    I::Item: Send,
+    R: Send,
+{
+    if settings.jobs == 0 {
+        return Err(Error::Usage("number of jobs must be greater than 0".into()));
+    }
+
+    let mut progress = settings.progress.then(|| match items.size_hint() {
+        (lower, Some(upper)) if lower == upper => Progress::new(Some(upper)),
+        _ => Progress::new(None),
+    });
+    let mut collector = Collector {
+        ordered: settings.ordered,
+        pending: BTreeMap::new(),
+        next: 0,
+        emit: |result| {
+            if let Some(progress) = &mut progress {
+                progress.advance();
+            }
+            emit(result)
+        },
+    };
+
+    let stop = AtomicBool::new(false);
+    // index of the first failed item, items after it aren't worked on
+    let failed = AtomicUsize::new(usize::MAX);
+    let window = Window {
+        size: settings
+            .ordered
+            .then(|| settings.jobs.saturating_mul(WINDOW)),
+        next: Mutex::new(0),
+        moved: Condvar::new(),
+    };
+    let (item_sender, item_receiver) = mpsc::sync_channel(settings.jobs);
+    let item_receiver = Mutex::new(item_receiver);
+    let (result_sender, results) = mpsc::channel();
+
+    std::thread::scope(|scope| {
+        let (stop, failed, window) = (&stop, &failed, &window);
+        let stopped =
+            move || stop.load(Ordering::SeqCst) || failed.load(Ordering::SeqCst) != usize::MAX;
+
+        scope.spawn(move || {
+            for (i, item) in items.enumerate() {
+                if !window.wait(i, stopped) || item_sender.send((i, item)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        for _ in 0..settings.jobs {
+            let (items, work, results) = (&item_receiver, &work, result_sender.clone());
+
+            scope.spawn(move || loop {
+                let next = items.lock().expect("item lock poisoned").recv();
+                let (i, item) = match next {
+                    Ok(next) => next,
+                    Err(mpsc::RecvError) => break,
+                };
+                // after an error the remaining items are only taken off the queue,
+                // the ones before a failed item still run, their results are emitted first
+                if stop.load(Ordering::SeqCst) || i > failed.load(Ordering::SeqCst) {
+                    continue;
+                }
+
+                let result = work(i, item);
+                if result.is_err() {
+                    failed.fetch_min(i, Ordering::SeqCst);
+                }
+                if results.send((i, result)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(result_sender);
+
+        let result = results.iter().try_for_each(|r| {
+            collector.add(r)?;
+            window.move_to(collector.next);
+            Ok(())
+        });
+        stop.store(true, Ordering::SeqCst);
+        window.move_to(collector.next);
+        result
+    })
+}
+
+/// The range of items that may be started, ahead of the next result to emit
+struct Window {
+    /// how many items may be started ahead, any number if `None`
+    size: Option<usize>,
+    /// index of the next result to emit
+    next: Mutex<usize>,
+    moved: Condvar,
+}
+
+impl Window {
+    /// Wait until item `i` may be started, returns `false` if `stopped` says to start no more items
+    fn wait(&self, i: usize, stopped: impl Fn() -> bool) -> bool {
+        let Some(size) = self.size else {
+            return !stopped();
+        };
+
+        let mut next = self.next.lock().expect("window lock poisoned");
+        while !stopped() && i >= next.saturating_add(size) {
+            next = self.moved.wait(next).expect("window lock poisoned");
+        }
+        !stopped()
+    }
+
+    /// Let items ahead of result `next` be started, also wakes up waiting threads to check if they should stop
+    fn move_to(&self, next: usize) {
+        *self.next.lock().expect("window lock poisoned") = next;
+        self.moved.notify_all();
+    }
+}
+
+/// Passes results to `emit`, in order of their items if `ordered`
+struct Collector<E, R> {
+    ordered: bool,
+    /// results waiting for the ones of earlier items
+    pending: BTreeMap<usize, Result<R>>,
+    /// index of the next result to emit
+    next: usize,
+    emit: E,
+}
+
+impl<E: FnMut(R) -> Result<()>, R> Collector<E, R> {
+    fn add(&mut self, (i, result): (usize, Result<R>)) -> Result<()> {
+        if !self.ordered {
+            return (self.emit)(result?);
+        }
+
+        self.pending.insert(i, result);
+        while let Some(result) = self.pending.remove(&self.next) {
+            (self.emit)(result?)?;
+            self.next += 1;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// One gate per item, item `i` waits until gate `i` is opened
+    struct Gates {
+        open: Vec<Mutex<mpsc::Sender<()>>>,
+        closed: Vec<Mutex<mpsc::Receiver<()>>>,
+    }
+
+    impl Gates {
+        fn new(n: usize) -> Self {
+            let (open, closed) = (0..n)
+                .map(|_| {
+                    let (sender, receiver) = mpsc::channel();
+                    (Mutex::new(sender), Mutex::new(receiver))
+                })
+                .unzip();
+            Gates { open, closed }
+        }
+
+        fn open(&self, i: usize) {
+            self.open[i].lock().unwrap().send(()).unwrap();
+        }
+
+        fn pass(&self, i: usize) {
+            self.closed[i].lock().unwrap().recv().unwrap();
+        }
+    }
+
+    fn settings(jobs: usize, ordered: bool) -> Settings {
+        Settings {
+            jobs,
+            ordered,
+            progress: false,
+        }
+    }
+
+    #[test]
+    fn ordered() {
+        // every item but the last waits for the next one to finish, so they finish in reverse
+        let gates = Gates::new(3);
+        let work = |i: usize, item: usize| {
+            if i < 2 {
+                gates.pass(i);
+            }
+            if i > 0 {
+                gates.open(i - 1);
+            }
+            Ok(item)
+        };
+
+        let mut emitted = Vec::new();
+        run(0..3, &settings(3, true), work, |r| {
+            emitted.push(r);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(emitted, [0, 1, 2]);
+    }
+
+    #[test]
+    fn unordered() {
+        // every item but the last waits for the next one to be emitted
+        let gates = Gates::new(3);
+        let work = |i: usize, item: usize| {
+            if i < 2 {
+                gates.pass(i);
+            }
+            Ok(item)
+        };
+
+        let mut emitted = Vec::new();
+        run(0..3, &settings(3, false), work, |r| {
+            if r > 0 {
+                gates.open(r - 1);
+            }
+            emitted.push(r);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(emitted, [2, 1, 0]);
+    }
+
+    #[test]
+    fn failure() {
+        let work = |i: usize, item: usize| match item {
+            3 => Err(Error::OutOfRange(i.to_string())),
+            _ => Ok(item),
+        };
+
+        let mut emitted = Vec::new();
+        let error = run(0..5, &settings(2, true), work, |r| {
+            emitted.push(r);
+            Ok(())
+        })
+        .unwrap_err();
+
+        assert!(matches!(error, Error::OutOfRange(i) if i == "3"));
+        assert_eq!(emitted, [0, 1, 2]);
+    }
+
+    #[test]
+    fn window() {
+        // the first item only finishes once the last item of the window started,
+        // no item after the window may start before that
+        let jobs = 2;
+        let size = jobs * WINDOW;
+        let gates = Gates::new(1);
+        let started = AtomicUsize::new(0);
+        let work = |i: usize, item: usize| {
+            started.fetch_max(i, Ordering::SeqCst);
+            match i {
+                0 => {
+                    gates.pass(0);
+                    assert_eq!(started.load(Ordering::SeqCst), size - 1);
+                }
+                i if i == size - 1 => gates.open(0),
+                _ => {}
+            }
+            Ok(item)
+        };
+
+        let mut emitted = Vec::new();
+        run(0..size * 2, &settings(jobs, true), work, |r| {
+            emitted.push(r);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(emitted, (0..size * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn no_jobs() {
+        let error = run(0..3, &settings(0, true), |_, item| Ok(item), |_| Ok(())).unwrap_err();
+        assert!(matches!(error, Error::Usage(_)));
+    }
+}
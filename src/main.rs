@@ -1,13 +1,25 @@
+mod batch;
+mod depfile;
+mod error;
 mod exec;
+mod jobs;
+mod placeholder;
+mod progress;
 mod templating;
+mod trace;
 mod util;
 
-use templating::template;
+use templating::{template, template_verified};
+
+use std::{io::BufRead, path::PathBuf, time::Duration};
 
 use itertools::join;
 use structopt::StructOpt;
 
-use crate::exec::{execute, execute_command};
+use crate::{
+    error::{Error, Result},
+    exec::{execute, execute_command},
+};
 
 #[derive(StructOpt, Debug)]
 enum CaseStyle {
@@ -17,13 +29,323 @@ enum CaseStyle {
     Upper,
 }
 
+/// Options of commands spawning processes
+#[derive(StructOpt, Debug)]
+struct ExecArgs {
+    #[structopt(long, default_value = "strict")]
+    /// what to do with output that isn't valid utf-8: strict, lossy or raw
+    decode: exec::Decoding,
+
+    #[structopt(long, default_value = "capture")]
+    /// where stderr of commands goes: inherit, capture (shown on failure), merge, discard or file:PATH
+    stderr: exec::Stderr,
+
+    #[structopt(long = "stderr-prefix")]
+    /// prefix each line of stderr with the block or input line that produced it, not with merge or discard
+    stderr_prefix: bool,
+
+    #[structopt(long)]
+    /// kill commands running longer than this many seconds
+    timeout: Option<f64>,
+
+    #[structopt(long = "propagate-exit-code")]
+    /// when a command fails, exit with its exit code instead of 4
+    propagate_exit_code: bool,
+
+    #[structopt(long = "limit-cpu")]
+    /// seconds of cpu time each command may use
+    limit_cpu: Option<u64>,
+
+    #[structopt(long = "limit-memory", parse(try_from_str = util::parse_size))]
+    /// address space each command may use, e.g. 512M
+    limit_memory: Option<u64>,
+
+    #[structopt(long = "limit-files")]
+    /// number of files each command may have open
+    limit_files: Option<u64>,
+
+    #[structopt(long = "limit-procs")]
+    /// number of processes the user may have while running a command
+    limit_procs: Option<u64>,
+
+    #[structopt(long = "limit-output", parse(try_from_str = util::parse_size))]
+    /// output each command may write, e.g. 10M, captured stderr is limited the same
+    limit_output: Option<u64>,
+
+    #[structopt(long, default_value = "0")]
+    /// how often a failing command is run again
+    retries: u32,
+
+    #[structopt(long = "retry-delay", default_value = "1")]
+    /// seconds to wait before the first retry, doubled for every following one up to an hour
+    retry_delay: f64,
+
+    #[structopt(long = "retry-on", use_delimiter = true)]
+    /// only retry on these exit codes, e.g. 1,75
+    retry_on: Vec<i32>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// write a line of JSON describing every spawned command to this file
+    trace: Option<PathBuf>,
+}
+
+impl ExecArgs {
+    fn options(&self) -> Result<exec::Options> {
+        if self.stderr_prefix && matches!(self.stderr, exec::Stderr::Merge | exec::Stderr::Discard)
+        {
+            return Err(Error::Usage(
+                "--stderr-prefix needs stderr to be inherited, captured or written to a file"
+                    .into(),
+            ));
+        }
+        if let exec::Stderr::File(path) = &self.stderr {
+            // start with an empty file, every process appends to it
+            std::fs::File::create(path)?;
+        }
+
+        let timeout = match self.timeout {
+            Some(seconds) if seconds > 0.0 => Some(
+                Duration::try_from_secs_f64(seconds)
+                    .map_err(|e| Error::Usage(format!("invalid timeout: {}", e)))?,
+            ),
+            Some(_) => return Err(Error::Usage("timeout must be a positive number".into())),
+            None => None,
+        };
+
+        if self.retry_delay < 0.0 {
+            return Err(Error::Usage("retry delay must not be negative".into()));
+        }
+        let retry_delay = Duration::try_from_secs_f64(self.retry_delay)
+            .map_err(|e| Error::Usage(format!("invalid retry delay: {}", e)))?;
+
+        Ok(exec::Options {
+            decoding: self.decode,
+            stderr: self.stderr.clone(),
+            stderr_prefix: self.stderr_prefix,
+            timeout,
+            limits: exec::Limits {
+                cpu: self.limit_cpu,
+                memory: self.limit_memory,
+                files: self.limit_files,
+                processes: self.limit_procs,
+                output: self.limit_output,
+            },
+            retry: exec::Retry {
+                attempts: self.retries,
+                delay: retry_delay,
+                exit_codes: self.retry_on.clone(),
+            },
+            trace: match &self.trace {
+                Some(path) => Some(trace::Trace::create(path)?),
+                None => None,
+            },
+            ..exec::Options::default()
+        })
+    }
+
+    /// Options for commands that only get printed in a dry run, which must not touch the stderr or trace file
+    fn dry_run_options(&self, dry_run: bool) -> Result<exec::Options> {
+        if dry_run {
+            Ok(exec::Options::default())
+        } else {
+            self.options()
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct JobArgs {
+    #[structopt(short, long, default_value = "1")]
+    /// how many commands to run at the same time
+    jobs: usize,
+
+    #[structopt(long)]
+    /// output results as commands complete instead of in input order
+    unordered: bool,
+
+    #[structopt(long)]
+    /// show how many commands are done, their rate and the remaining time on stderr, if it is a terminal
+    progress: bool,
+
+    #[structopt(long = "force-progress")]
+    /// show the progress even if stderr is not a terminal
+    force_progress: bool,
+}
+
+impl JobArgs {
+    /// Items to run jobs for, read ahead of time if the progress needs to know their number
+    fn items<'a, T: Send + 'a>(
+        &self,
+        items: &'a mut (impl Iterator<Item = T> + Send),
+    ) -> Box<dyn Iterator<Item = T> + Send + 'a> {
+        if self.settings().progress {
+            Box::new(items.collect::<Vec<_>>().into_iter())
+        } else {
+            Box::new(items)
+        }
+    }
+
+    fn settings(&self) -> jobs::Settings {
+        jobs::Settings {
+            jobs: self.jobs,
+            ordered: !self.unordered,
+            progress: self.force_progress
+                || (self.progress && std::io::IsTerminal::is_terminal(&std::io::stderr())),
+        }
+    }
+}
+
+/// Separators of records, for commands working on lines
+#[derive(StructOpt, Debug)]
+struct SeparatorArgs {
+    #[structopt(short = "0", long, conflicts_with = "record-separator")]
+    /// separate records by NUL instead of newline, like `find -print0` does
+    null: bool,
+
+    #[structopt(long = "record-separator", parse(try_from_str = util::parse_separator))]
+    /// separate input records by this instead of newline
+    record_separator: Option<String>,
+
+    #[structopt(long = "output-separator", parse(try_from_str = util::parse_separator))]
+    /// separate output records by this instead of the record separator
+    output_separator: Option<String>,
+}
+
+impl SeparatorArgs {
+    fn input(&self) -> &str {
+        match &self.record_separator {
+            _ if self.null => "\0",
+            Some(separator) => separator,
+            None => "\n",
+        }
+    }
+
+    fn output(&self) -> &str {
+        self.output_separator
+            .as_deref()
+            .unwrap_or_else(|| self.input())
+    }
+
+    /// Records of `input` without their separators, newline separated records may end with `\r\n`
+    fn records<'a>(&'a self, input: &'a str) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        match self.input() {
+            "\n" => Box::new(input.lines()),
+            separator => Box::new(input.split_terminator(separator)),
+        }
+    }
+
+    /// Records read from `input` one at a time
+    fn reader<R: BufRead>(&self, input: R) -> util::Records<'_, R> {
+        util::Records::new(input, self.input())
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct FailureArgs {
+    #[structopt(long = "keep-going")]
+    /// keep running commands after one failed, report all failures at the end
+    keep_going: bool,
+
+    #[structopt(long = "failure-marker", requires = "keep-going")]
+    /// output in place of the output of a failed command, requires --keep-going, empty by default
+    failure_marker: Option<String>,
+}
+
+impl FailureArgs {
+    /// Run `work` for all `items` and write each output followed by `separator`.
+    /// `origin` tells where an item comes from, for reporting its failure.
+    /// When keeping going, failed items are written as the failure marker and returned as one error at the end.
+    fn run<I, W>(
+        &self,
+        items: I,
+        jobs: &JobArgs,
+        origin: impl Fn(usize, &I::Item) -> exec::Origin + Sync,
+        work: impl Fn(usize, I::Item) -> Result<Vec<u8>> + Sync,
+        output: &mut W,
+        separator: &str,
+    ) -> Result<()>
+    where
+        I: Iterator + Send,
+        I::Item: Send,
+        W: std::io::Write,
+    {
+        if !self.keep_going {
+            return jobs::run(items, &jobs.settings(), work, |result| {
+                write_record(output, &result, separator)
+            });
+        }
+
+        let mut failures = Vec::new();
+        let mut total = 0;
+        let work = |i, item| {
+            let origin = origin(i, &item);
+            Ok(work(i, item).map_err(|e| (i, origin.to_string(), e)))
+        };
+
+        jobs::run(items, &jobs.settings(), work, |result| {
+            total += 1;
+            match result {
+                Ok(result) => write_record(output, &result, separator),
+                Err(failure) => {
+                    failures.push(failure);
+                    let marker = self.failure_marker.as_deref().unwrap_or_default();
+                    write_record(output, marker.as_bytes(), separator)
+                }
+            }
+        })?;
+
+        if failures.is_empty() {
+            return Ok(());
+        }
+
+        // results may come unordered
+        failures.sort_by_key(|(i, _, _)| *i);
+        Err(Error::Failures {
+            failures: failures
+                .into_iter()
+                .map(|(_, origin, e)| (origin, e))
+                .collect(),
+            total,
+        })
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct PlaceholderArgs {
+    #[structopt(long, default_value = "__var")]
+    /// token in the command which gets substituted with each line, escape it with a backslash to keep it,
+    /// which needs quoting itself in a command given as a single argument
+    placeholder: String,
+
+    #[structopt(long)]
+    /// shell-escape substituted lines in every argument, only meant for commands like `sh -c 'echo {}'`
+    quote: bool,
+
+    #[structopt(long = "field-separator")]
+    /// separator of the fields picked by placeholders like `{1}`, whitespace by default
+    field_separator: Option<String>,
+}
+
+impl PlaceholderArgs {
+    fn placeholder(&self) -> Result<placeholder::Placeholder> {
+        placeholder::Placeholder::new(
+            &self.placeholder,
+            self.quote,
+            self.field_separator.as_deref(),
+        )
+    }
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(about = "Cli for common string operations. Takes input from stdin.")]
 enum StringCommand {
     /// Transform upper- or lowercase
     Case(CaseStyle),
     /// Reverse order of lines
-    Reverse,
+    Reverse {
+        #[structopt(flatten)]
+        separators: SeparatorArgs,
+    },
     /// Extract a part of a given string.
     Substr {
         #[structopt()]
@@ -56,15 +378,24 @@ enum StringCommand {
         #[structopt()]
         /// starting at 0
         n: usize,
+
+        #[structopt(flatten)]
+        separators: SeparatorArgs,
     },
     /// Output the set of input strings without repetitions, in order
     Distinct {
         #[structopt(short)]
         /// Distinct entire lines, instead of individual words
         lines: bool,
+
+        #[structopt(flatten)]
+        separators: SeparatorArgs,
     },
     /// Trim whitespace on lines and ignore empty ones
-    Trim,
+    Trim {
+        #[structopt(flatten)]
+        separators: SeparatorArgs,
+    },
     /// Prints all chars on separate lines
     Chars,
     /// Useful for templating, replace sections of input with the output of a shell command or script
@@ -78,40 +409,613 @@ enum StringCommand {
         end: String,
 
         #[structopt(default_value = "sh", long)]
-        /// in which shell the commands should be piped
-        shell: Vec<String>,
+        /// in which shell the commands should be piped, may contain arguments, e.g. "bash -eu"
+        shell: String,
 
         #[structopt(long = "raw-output")]
         /// don't trim new lines and whitespace of the start and end of output
         raw_output: bool,
+
+        #[structopt(long)]
+        /// pin locale, timezone and SOURCE_DATE_EPOCH of the commands
+        reproducible: bool,
+
+        #[structopt(long = "verify-reproducible")]
+        /// render twice and fail if the outputs differ
+        verify_reproducible: bool,
+
+        #[structopt(long, parse(from_os_str))]
+        /// read the template from this file instead of stdin
+        file: Option<PathBuf>,
+
+        #[structopt(long, parse(from_os_str), requires = "file")]
+        /// write a Makefile style list of the files the render depends on, requires --file
+        depfile: Option<PathBuf>,
+
+        #[structopt(long = "depfile-target")]
+        /// target of the dependency file, defaults to the depfile path without extension
+        depfile_target: Option<String>,
+
+        #[structopt(flatten)]
+        exec: ExecArgs,
     },
     /// Maps each line of input to a given command.
     /// The input will be supplied as stdin of the command.
+    /// A command given as a single argument is split into words like a shell would.
     Map {
         #[structopt()]
         command: Vec<String>,
+
+        #[structopt(long)]
+        /// start the command once and send it every line on stdin, instead of starting it for each line
+        coprocess: bool,
+
+        #[structopt(long)]
+        /// how the coprocess ends each answer: newline (the default), nul or a line containing only the given text
+        delimiter: Option<exec::Delimiter>,
+
+        #[structopt(long = "dry-run")]
+        /// print the commands shell-quoted instead of running them
+        dry_run: bool,
+
+        #[structopt(flatten)]
+        separators: SeparatorArgs,
+
+        #[structopt(flatten)]
+        jobs: JobArgs,
+
+        #[structopt(flatten)]
+        failure: FailureArgs,
+
+        #[structopt(flatten)]
+        exec: ExecArgs,
     },
     /// Applies a command to each line of input.
     /// Lines won't get applied as stdin to the command,
-    /// instead the command may contain the token "__var", which will get substituted with the individual lines.
+    /// instead the command may contain a placeholder, "__var" by default, which will get substituted with the individual lines.
+    /// Placeholders like "{}" also come as "{#}" and "{i}" for the number and index of the line, "{1}" for its first field,
+    /// "{/}", "{//}", "{.}", "{/.}" and "{+.}" for its basename, dirname, path without extension, basename without extension and extension.
+    /// A command given as a single argument is split into words like a shell would.
     Foreach {
         #[structopt()]
         command: Vec<String>,
+
+        #[structopt(flatten)]
+        placeholder: PlaceholderArgs,
+
+        #[structopt(long, alias = "max-args")]
+        /// pass up to this many lines to each command, to arguments which are just a placeholder or appended to the command
+        batch: Option<usize>,
+
+        #[structopt(long = "max-chars")]
+        /// pass as many lines to each command as fit into a command line of this many bytes, at most the system limit
+        max_chars: Option<usize>,
+
+        #[structopt(long = "dry-run")]
+        /// print the commands shell-quoted instead of running them
+        dry_run: bool,
+
+        #[structopt(flatten)]
+        separators: SeparatorArgs,
+
+        #[structopt(flatten)]
+        jobs: JobArgs,
+
+        #[structopt(flatten)]
+        failure: FailureArgs,
+
+        #[structopt(flatten)]
+        exec: ExecArgs,
+    },
+    /// Keeps the lines of input for which a given command succeeds.
+    /// Lines are supplied as stdin of the command, unless the command contains a placeholder like foreach.
+    /// A command given as a single argument is split into words like a shell would.
+    Filter {
+        #[structopt()]
+        command: Vec<String>,
+
+        #[structopt(short = "v", long)]
+        /// keep the lines for which the command fails instead
+        invert: bool,
+
+        #[structopt(flatten)]
+        placeholder: PlaceholderArgs,
+
+        #[structopt(flatten)]
+        separators: SeparatorArgs,
+
+        #[structopt(flatten)]
+        jobs: JobArgs,
+
+        #[structopt(flatten)]
+        exec: ExecArgs,
     },
 }
 
-fn main() -> std::io::Result<()> {
-    let command: StringCommand = StringCommand::from_args();
-    let input = util::stdin_as_string();
-    let mut output = std::io::stdout();
+fn main() {
+    let command = match StringCommand::from_iter_safe(std::env::args_os()) {
+        Ok(command) => command,
+        Err(e) if e.use_stderr() => {
+            eprintln!("{}", e.message);
+            std::process::exit(Error::Usage(String::new()).exit_code());
+        }
+        // help and version
+        Err(e) => e.exit(),
+    };
+
+    let propagate = match &command {
+        StringCommand::Template { exec, .. }
+        | StringCommand::Map { exec, .. }
+        | StringCommand::Foreach { exec, .. } => exec.propagate_exit_code,
+        _ => false,
+    };
 
-    perform_command(command, input, &mut output)
+    // not locked, commands may read it on a thread of its own
+    let input = std::io::BufReader::new(std::io::stdin());
+    let mut output = std::io::stdout().lock();
+
+    if let Err(e) = perform_command(command, input, &mut output) {
+        eprintln!("{}", e);
+
+        let code = match e.child_exit_code() {
+            Some(code) if propagate => code,
+            _ => e.exit_code(),
+        };
+        std::process::exit(code);
+    }
+}
+
+fn perform_command(
+    command: StringCommand,
+    mut input: impl BufRead + Send,
+    output: &mut impl std::io::Write,
+) -> Result<()> {
+    use StringCommand::*;
+
+    if let Template {
+        shell,
+        begin,
+        end,
+        raw_output,
+        reproducible,
+        verify_reproducible,
+        file,
+        depfile,
+        depfile_target,
+        exec,
+    } = command
+    {
+        let input: Box<dyn BufRead> = match &file {
+            Some(path) => Box::new(std::io::BufReader::new(std::fs::File::open(path)?)),
+            None => Box::new(input),
+        };
+
+        let shell = util::shell_words(&shell)?;
+        let shell: Vec<&str> = shell.iter().map(|s| s.as_str()).collect();
+        let settings = templating::Settings {
+            shell: &shell,
+            begin: &begin,
+            end: &end,
+            trim: !raw_output,
+            exec: if reproducible {
+                exec.options()?.reproducible()?
+            } else {
+                exec.options()?
+            },
+            file: file.as_deref(),
+        };
+
+        if verify_reproducible {
+            template_verified(input, output, &settings)?;
+        } else {
+            template(input, output, &settings)?;
+        }
+
+        if let Some(path) = depfile {
+            let target = depfile_target.unwrap_or_else(|| depfile::default_target(&path));
+            let dependencies: Vec<_> = file.iter().map(|p| p.as_path()).collect();
+
+            depfile::write_depfile(&path, &target, &dependencies)?;
+        }
+
+        writeln!(output)?;
+        return Ok(());
+    }
+
+    // line oriented commands stream their input, the others work on all of it
+    match command {
+        Case(c) => {
+            let mut line = String::new();
+            while util::read_line(&mut input, &mut line)? > 0 {
+                match c {
+                    CaseStyle::Lower => write!(output, "{}", line.to_lowercase())?,
+                    CaseStyle::Upper => write!(output, "{}", line.to_uppercase())?,
+                }
+                line.clear();
+            }
+        }
+        Trim { separators } => {
+            let mut records = separators.reader(input);
+
+            for line in &mut records {
+                let line = line.trim();
+                if !line.is_empty() {
+                    write_record(output, line.as_bytes(), separators.output())?;
+                }
+            }
+            records.finish()?;
+        }
+        Interleave { n, separators } => {
+            if n == 0 {
+                return Err(Error::Usage("n must be greater than 0".into()));
+            }
+
+            let mut records = separators.reader(input);
+            for (i, line) in (&mut records).enumerate() {
+                if i % n == 0 {
+                    write_record(output, line.as_bytes(), separators.output())?;
+                }
+            }
+            records.finish()?;
+        }
+        Distinct { lines, separators } => {
+            let mut set = std::collections::BTreeSet::new();
+            let mut distinct = |word: &str| {
+                if set.contains(word) {
+                    return Ok(());
+                }
+
+                set.insert(word.to_string());
+                write_record(output, word.as_bytes(), separators.output())
+            };
+
+            // words never span records
+            let mut records = separators.reader(input);
+            for record in &mut records {
+                if lines {
+                    distinct(&record)?;
+                    continue;
+                }
+                for word in record.split_terminator(&[' ', '\r', '\n', '\t'][..]) {
+                    distinct(word)?;
+                }
+            }
+            records.finish()?;
+        }
+        Line { number } => writeln!(output, "{}", pick_line(input, number)?)?,
+        Chars => {
+            let mut line = String::new();
+            while util::read_line(&mut input, &mut line)? > 0 {
+                for c in line.chars() {
+                    writeln!(output, "{}", c)?;
+                }
+                line.clear();
+            }
+        }
+        Map {
+            command,
+            coprocess: true,
+            delimiter,
+            dry_run,
+            separators,
+            jobs,
+            failure,
+            exec,
+        } => {
+            if jobs.jobs != 1 {
+                return Err(Error::Usage("a coprocess can't run multiple jobs".into()));
+            }
+            let unsupported = [
+                ("--keep-going", failure.keep_going),
+                ("--progress", jobs.progress || jobs.force_progress),
+            ];
+            if let Some((option, _)) = unsupported.iter().find(|(_, given)| *given) {
+                return Err(Error::Usage(format!(
+                    "{} doesn't apply to a coprocess",
+                    option
+                )));
+            }
+
+            let command = util::command_words(command)?;
+            let mut records = separators.reader(input);
+
+            if dry_run {
+                let lines: String = (&mut records)
+                    .flat_map(|line| [line, "\n".into()])
+                    .collect();
+                records.finish()?;
+                writeln!(output, "{}", exec::shell_line(Some(&lines), &command))?;
+                return Ok(());
+            }
+
+            let mut worker =
+                exec::Coprocess::start(&command, delimiter.unwrap_or_default(), &exec.options()?)?;
+
+            // the coprocess reads lines, records containing a newline are refused
+            for line in &mut records {
+                let result = worker.call(&line)?;
+                write_record(output, &result, separators.output())?;
+            }
+
+            records.finish()?;
+            worker.finish()?;
+        }
+        Map {
+            command,
+            dry_run,
+            separators,
+            jobs,
+            delimiter,
+            failure,
+            exec,
+            ..
+        } => {
+            if delimiter.is_some() {
+                return Err(Error::Usage(
+                    "--delimiter only applies to --coprocess".into(),
+                ));
+            }
+            let command = util::command_words(command)?;
+            let shell: Vec<&str> = command.iter().map(|s| s.as_str()).collect();
+            let options = exec.dry_run_options(dry_run)?;
+
+            let work = |i: usize, line: String| {
+                let options = exec::Options {
+                    origin: Some(exec::Origin::Line(i + 1)),
+                    ..options.clone()
+                };
+
+                if dry_run {
+                    return Ok(exec::shell_line(Some(&line), &shell).into_bytes());
+                }
+                execute(&line, &shell, &options)
+            };
+
+            let origin = |i: usize, _: &String| exec::Origin::Line(i + 1);
+            let mut records = separators.reader(input);
+            let lines = jobs.items(&mut records);
+            failure.run(lines, &jobs, origin, work, output, separators.output())?;
+            records.finish()?;
+        }
+        Foreach {
+            command,
+            placeholder,
+            batch,
+            max_chars,
+            dry_run,
+            separators,
+            jobs,
+            failure,
+            exec,
+        } => {
+            let command = util::command_words(command)?;
+            let placeholder = placeholder.placeholder()?;
+            let options = exec.dry_run_options(dry_run)?;
+            let run_command = |command: &[String], options: &exec::Options| {
+                if dry_run {
+                    return Ok(exec::shell_line(None, command).into_bytes());
+                }
+                execute_command(command, options)
+            };
+            let mut records = separators.reader(input);
+
+            if batch.is_none() && max_chars.is_none() {
+                let work = |index, text: String| {
+                    let line = placeholder::Line { text: &text, index };
+                    let options = exec::Options {
+                        origin: Some(exec::Origin::Line(index + 1)),
+                        ..options.clone()
+                    };
+                    let command: Vec<_> = command
+                        .iter()
+                        .map(|arg| placeholder.substitute(arg, line))
+                        .collect();
+
+                    run_command(&command, &options)
+                };
+
+                let origin = |i: usize, _: &String| exec::Origin::Line(i + 1);
+                let lines = jobs.items(&mut records);
+                failure.run(lines, &jobs, origin, work, output, separators.output())?;
+            } else {
+                let limits = batch::Limits::new(batch, max_chars)?;
+                let base = batch::size(&placeholder.batch(&command, &[])?);
+                let size = |(index, text): &(usize, String)| {
+                    let line = placeholder::Line {
+                        text,
+                        index: *index,
+                    };
+                    let args = placeholder.batch(&command, &[line]).unwrap_or_default();
+                    batch::size(&args).saturating_sub(base)
+                };
+
+                let work = |_, batch: Vec<(usize, String)>| {
+                    let lines = batch_lines(&batch);
+                    let options = exec::Options {
+                        origin: Some(batch_origin(&lines)),
+                        ..options.clone()
+                    };
+
+                    run_command(&placeholder.batch(&command, &lines)?, &options)
+                };
+
+                let lines = (&mut records).enumerate();
+                let mut batches = batch::batches(lines, base, limits, size);
+                let batches = jobs.items(&mut batches);
+                let origin = |_, batch: &Vec<_>| batch_origin(&batch_lines(batch));
+                failure.run(batches, &jobs, origin, work, output, separators.output())?;
+            }
+
+            records.finish()?;
+        }
+        Filter {
+            command,
+            invert,
+            placeholder,
+            separators,
+            jobs,
+            exec,
+        } => {
+            if exec.propagate_exit_code {
+                return Err(Error::Usage(
+                    "--propagate-exit-code doesn't apply to filter, failing commands only drop their line"
+                        .into(),
+                ));
+            }
+            let command = util::command_words(command)?;
+            let placeholder = placeholder.placeholder()?;
+            let substitute = command.iter().any(|arg| placeholder.is_in(arg));
+            let shell: Vec<&str> = command.iter().map(|s| s.as_str()).collect();
+            let options = exec::Options {
+                // only the exit status matters
+                decoding: exec::Decoding::Raw,
+                ..exec.options()?
+            };
+
+            let work = |i, line: String| {
+                let options = exec::Options {
+                    origin: Some(exec::Origin::Line(i + 1)),
+                    ..options.clone()
+                };
+
+                let result = if substitute {
+                    let line = placeholder::Line {
+                        text: &line,
+                        index: i,
+                    };
+                    let command: Vec<_> = command
+                        .iter()
+                        .map(|arg| placeholder.substitute(arg, line))
+                        .collect();
+
+                    execute_command(&command, &options)
+                } else {
+                    execute(&line, &shell, &options)
+                };
+
+                let success = match result {
+                    Ok(_) => true,
+                    Err(Error::Exit { .. }) => false,
+                    Err(e) => return Err(e),
+                };
+                Ok((success != invert).then_some(line))
+            };
+
+            let mut records = separators.reader(input);
+            jobs::run(jobs.items(&mut records), &jobs.settings(), work, |line| {
+                if let Some(line) = line {
+                    write_record(output, line.as_bytes(), separators.output())?;
+                }
+                Ok(())
+            })?;
+            records.finish()?;
+        }
+        command => perform_on_whole_input(command, &util::read_to_string(input)?, output)?,
+    };
+
+    Ok(())
+}
+
+/// Perform a command which needs all of the input at once
+fn perform_on_whole_input(
+    command: StringCommand,
+    input: &str,
+    output: &mut impl std::io::Write,
+) -> Result<()> {
+    use StringCommand::*;
+
+    match command {
+        Reverse { separators } => {
+            for line in separators
+                .records(input)
+                .collect::<Vec<_>>()
+                .iter()
+                .rev()
+                .filter(|l| !l.is_empty())
+            {
+                write_record(output, line.as_bytes(), separators.output())?;
+            }
+        }
+        Substr { start, end } => {
+            writeln!(output, "{}", substr(input, start, end)?)?;
+        }
+        Split { separator } => {
+            let result = join(input.split(&separator), "\n");
+            write!(output, "{}", result)?;
+        }
+        Length => writeln!(output, "{}", input.len())?,
+        Replace { matching, with } => {
+            let result = join(input.split(&matching), &with);
+            write!(output, "{}", result)?;
+        }
+        _ => unreachable!("streamed above"),
+    };
+
+    Ok(())
+}
+
+/// Write a record followed by its separator
+fn write_record(output: &mut impl std::io::Write, record: &[u8], separator: &str) -> Result<()> {
+    output.write_all(record)?;
+    Ok(output.write_all(separator.as_bytes())?)
+}
+
+/// Lines of a batch of numbered records
+fn batch_lines(batch: &[(usize, String)]) -> Vec<placeholder::Line<'_>> {
+    batch
+        .iter()
+        .map(|(index, text)| placeholder::Line {
+            text,
+            index: *index,
+        })
+        .collect()
+}
+
+/// Where a batch of lines comes from
+fn batch_origin(lines: &[placeholder::Line]) -> exec::Origin {
+    exec::Origin::Lines {
+        first: lines[0].index + 1,
+        last: lines[lines.len() - 1].index + 1,
+    }
+}
+
+/// Line `number` of `input` starting at 0, reading no further than that line
+fn pick_line(mut input: impl BufRead, number: usize) -> Result<String> {
+    let mut line = String::new();
+
+    for i in 0..=number {
+        line.clear();
+        util::read_line(&mut input, &mut line)?;
+
+        // only a newline means there is another line, if just an empty one
+        if i < number && !line.ends_with('\n') {
+            return Err(Error::OutOfRange("input does not have enough lines".into()));
+        }
+    }
+
+    if line.ends_with('\n') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+fn substr(input: &str, start: usize, end: usize) -> Result<String> {
+    if start > end {
+        return Err(Error::Usage(
+            "start value must be smaller than end value".into(),
+        ));
+    }
+
+    let amount = end - start;
+
+    Ok(input.chars().skip(start).take(amount).collect())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{perform_command, StringCommand, StringCommand::*};
+    use super::{perform_command, Error, StringCommand, StringCommand::*};
     use std::fmt::Formatter;
+    use structopt::StructOpt;
 
     struct TestWriter {
         buffer: Vec<u8>,
@@ -144,6 +1048,10 @@ mod tests {
         }
     }
 
+    fn parse(args: &[&str]) -> StringCommand {
+        StringCommand::from_iter(std::iter::once("string").chain(args.iter().copied()))
+    }
+
     impl PartialEq<&str> for TestWriter {
         fn eq(&self, other: &&str) -> bool {
             self.buffer == other.as_bytes()
@@ -163,7 +1071,7 @@ mod tests {
 
         for (input, expected) in cases {
             let mut writer = TestWriter::new();
-            perform_command(Reverse, input.into(), &mut writer).unwrap();
+            perform_command(parse(&["reverse"]), input.as_bytes(), &mut writer).unwrap();
             assert_eq!(writer, expected);
         }
     }
@@ -180,7 +1088,7 @@ mod tests {
 
         for (input, expected) in cases {
             let mut writer = TestWriter::new();
-            perform_command(Distinct { lines: false }, input.into(), &mut writer).unwrap();
+            perform_command(parse(&["distinct"]), input.as_bytes(), &mut writer).unwrap();
             assert_eq!(writer, expected);
         }
     }
@@ -198,7 +1106,7 @@ mod tests {
 
         for (input, expected) in cases {
             let mut writer = TestWriter::new();
-            perform_command(Distinct { lines: true }, input.into(), &mut writer).unwrap();
+            perform_command(parse(&["distinct", "-l"]), input.as_bytes(), &mut writer).unwrap();
             assert_eq!(writer, expected);
         }
     }
@@ -217,7 +1125,7 @@ mod tests {
 
         for (input, expected) in cases {
             let mut writer = TestWriter::new();
-            perform_command(Substr { start: 2, end: 4 }, input.into(), &mut writer).unwrap();
+            perform_command(Substr { start: 2, end: 4 }, input.as_bytes(), &mut writer).unwrap();
             assert_eq!(writer, expected);
         }
     }
@@ -235,7 +1143,7 @@ mod tests {
             let mut writer = TestWriter::new();
             perform_command(
                 StringCommand::Case(super::CaseStyle::Lower),
-                input.into(),
+                input.as_bytes(),
                 &mut writer,
             )
             .unwrap();
@@ -256,7 +1164,7 @@ mod tests {
             let mut writer = TestWriter::new();
             perform_command(
                 StringCommand::Case(super::CaseStyle::Upper),
-                input.into(),
+                input.as_bytes(),
                 &mut writer,
             )
             .unwrap();
@@ -274,144 +1182,472 @@ mod tests {
         let expected = "Hello\nWorld\n";
 
         let mut writer = TestWriter::new();
-        perform_command(Trim, input.into(), &mut writer).unwrap();
+        perform_command(parse(&["trim"]), input.as_bytes(), &mut writer).unwrap();
         assert_eq!(writer, expected);
     }
-}
 
-fn perform_command(
-    command: StringCommand,
-    input: String,
-    output: &mut impl std::io::Write,
-) -> std::io::Result<()> {
-    use StringCommand::*;
-    match command {
-        Case(c) => match c {
-            CaseStyle::Lower => {
-                let input = input.to_lowercase();
-                write!(output, "{}", input)?;
-            }
-            CaseStyle::Upper => {
-                let input = input.to_uppercase();
-                write!(output, "{}", input)?;
-            }
-        },
-        Reverse => {
-            for line in input
-                .split('\n')
-                .collect::<Vec<_>>()
-                .iter()
-                .rev()
-                .filter(|l| !l.is_empty())
-            {
-                writeln!(output, "{}", line)?;
-            }
-        }
-        Trim => {
-            for line in input
-                .lines()
-                .map(|line| line.trim())
-                .filter(|line| !line.is_empty())
-            {
-                writeln!(output, "{}", line)?
-            }
-        }
-        Interleave { n } => {
-            for (i, line) in input.lines().enumerate() {
-                if i % n == 0 {
-                    writeln!(output, "{}", line)?
-                }
-            }
-        }
-        Distinct { lines } => {
-            let mut set = std::collections::BTreeSet::new();
+    #[test]
+    fn line() {
+        let mut writer = TestWriter::new();
+        perform_command(Line { number: 1 }, "a\nb\nc".as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "b\n");
+    }
 
-            let separator = if lines {
-                &['\n'][..]
-            } else {
-                &[' ', '\r', '\n', '\t'][..]
-            };
+    #[test]
+    fn line_out_of_range() {
+        let mut writer = TestWriter::new();
+        let result = perform_command(Line { number: 3 }, "a\nb\nc".as_bytes(), &mut writer);
+        assert!(matches!(result, Err(Error::OutOfRange(_))));
+    }
 
-            for line in input.split_terminator(separator) {
-                if set.get(line).is_some() {
-                    continue;
-                }
+    #[test]
+    fn substring_reversed() {
+        let mut writer = TestWriter::new();
+        let result = perform_command(
+            Substr { start: 4, end: 2 },
+            "abcdef".as_bytes(),
+            &mut writer,
+        );
+        assert!(matches!(result, Err(Error::Usage(_))));
+    }
 
-                set.insert(line);
-                writeln!(output, "{}", line)?;
-            }
-        }
-        Substr { start, end } => {
-            writeln!(output, "{}", substr(&input, start, end))?;
+    #[test]
+    fn invalid_utf8_input() {
+        let mut writer = TestWriter::new();
+        let result = perform_command(parse(&["trim"]), &b"\xff\xfe"[..], &mut writer);
+        assert!(matches!(result, Err(Error::InvalidUtf8 { .. })));
+    }
+
+    #[test]
+    fn map_lossy() {
+        let command = parse(&[
+            "map",
+            "--decode",
+            "lossy",
+            "--",
+            "sh",
+            "-c",
+            "printf '\\377'",
+        ]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, "a".as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "\u{FFFD}\n");
+    }
+
+    #[test]
+    fn map_single_argument() {
+        let command = parse(&["map", "sed -e 's/a b/c/'"]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, "a b\nb a".as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "c\nb a\n");
+    }
+
+    #[test]
+    fn template_shell_with_arguments() {
+        let command = parse(&["template", "--shell", "sh -eu"]);
+
+        let mut writer = TestWriter::new();
+        let result = perform_command(
+            command,
+            "{{ echo $UNSET_VARIABLE }}".as_bytes(),
+            &mut writer,
+        );
+        assert!(matches!(result, Err(Error::Exit { .. })));
+    }
+
+    #[test]
+    fn template_depfile() {
+        let dir = std::env::temp_dir();
+        let template = dir.join(format!("string-depfile-{}.yaml", std::process::id()));
+        let depfile = dir.join(format!("string-depfile-{}.d", std::process::id()));
+        std::fs::write(&template, "a: {{ echo 1 }}").unwrap();
+
+        let command = parse(&[
+            "template",
+            "--file",
+            template.to_str().unwrap(),
+            "--depfile",
+            depfile.to_str().unwrap(),
+            "--depfile-target",
+            "out.yaml",
+        ]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, "".as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "a: 1\n");
+        assert_eq!(
+            std::fs::read_to_string(&depfile).unwrap(),
+            format!("out.yaml: {0}\n\n{0}:\n", template.display())
+        );
+
+        std::fs::remove_file(template).unwrap();
+        std::fs::remove_file(depfile).unwrap();
+
+        // without a template file there is nothing to depend on
+        let args = ["string", "template", "--depfile", "out.d"];
+        assert!(StringCommand::from_iter_safe(args).is_err());
+    }
+
+    #[test]
+    fn map_large_line() {
+        // larger than any pipe buffer, used to deadlock
+        let mut input = "0123456789".repeat(512 * 1024);
+        input.push_str("\nsmall");
+
+        let mut writer = TestWriter::new();
+        perform_command(parse(&["map", "cat"]), input.as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, format!("{}\n", input).as_str());
+    }
+
+    #[test]
+    fn map_coprocess() {
+        let command = parse(&[
+            "map",
+            "--coprocess",
+            "--delimiter",
+            "nul",
+            "--",
+            "sh",
+            "-c",
+            "while read l; do printf '<%s>\\0' \"$l\"; done",
+        ]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, "a\nb c\n".as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "<a>\n<b c>\n");
+
+        let command = parse(&["map", "--coprocess", "-0", "cat"]);
+        let result = perform_command(command, "a\0b\nc\0".as_bytes(), &mut TestWriter::new());
+        assert!(matches!(result, Err(Error::Coprocess { .. })));
+    }
+
+    #[test]
+    fn map_coprocess_unsupported() {
+        for args in [
+            &["map", "--coprocess", "-j", "2", "cat"][..],
+            &["map", "--coprocess", "--keep-going", "cat"],
+            &["map", "--coprocess", "--force-progress", "cat"],
+            &["map", "--coprocess", "--timeout", "1", "cat"],
+            &["map", "--coprocess", "--retries", "1", "cat"],
+            &["map", "--coprocess", "--limit-output", "1k", "cat"],
+            &["map", "--delimiter", "nul", "cat"],
+        ] {
+            let result = perform_command(parse(args), "a\n".as_bytes(), &mut TestWriter::new());
+            assert!(matches!(result, Err(Error::Usage(_))), "{:?}", args);
         }
-        Split { separator } => {
-            let result = join(input.split(&separator), "\n");
-            write!(output, "{}", result)?;
+    }
+
+    #[test]
+    fn stderr_prefix_unsupported() {
+        for stderr in ["merge", "discard"] {
+            let args = ["map", "--stderr", stderr, "--stderr-prefix", "cat"];
+            let result = perform_command(parse(&args), "a\n".as_bytes(), &mut TestWriter::new());
+            assert!(matches!(result, Err(Error::Usage(_))), "{}", stderr);
         }
-        Length => writeln!(output, "{}", input.len())?,
-        Replace { matching, with } => {
-            let result = join(input.split(&matching), &with);
-            write!(output, "{}", result)?;
+    }
+
+    #[test]
+    fn map_jobs() {
+        let input = "3\n1\n2\n";
+        let command = parse(&["map", "-j", "3", "sh -c 'read s; sleep 0.$s; printf $s'"]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, input.as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, input);
+
+        // the order of unordered results depends on timing, only which results there are doesn't
+        let command = parse(&["foreach", "-j", "3", "--unordered", "printf __var"]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, input.as_bytes(), &mut writer).unwrap();
+        let output = String::from_utf8(writer.buffer).unwrap();
+        let mut results: Vec<_> = output.lines().collect();
+        results.sort_unstable();
+        assert_eq!(results, ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn foreach_placeholder() {
+        let input = "it's\n";
+        let command = parse(&[
+            "foreach",
+            "--placeholder",
+            "{}",
+            "--quote",
+            "--",
+            "sh",
+            "-c",
+            "printf '%s \\{}' {}",
+        ]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, input.as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "it's {}\n");
+    }
+
+    #[test]
+    fn foreach_escaped_placeholder() {
+        // splitting a single argument into words removes unquoted backslashes
+        let cases = [
+            (r"echo \__var __var", "x x\n\n"),
+            (r"echo \\__var __var", "__var x\n\n"),
+            (r"echo '\__var' __var", "__var x\n\n"),
+        ];
+
+        for (command, expected) in cases {
+            let mut writer = TestWriter::new();
+            perform_command(parse(&["foreach", command]), "x\n".as_bytes(), &mut writer).unwrap();
+            assert_eq!(writer, expected, "{}", command);
         }
-        Line { number } => writeln!(output, "{}", pick_line(&input, number))?,
-        Template {
-            shell,
-            begin,
-            end,
-            raw_output,
-        } => {
-            let shell: Vec<&str> = shell.iter().map(|s| s.as_str()).collect();
+    }
+
+    #[test]
+    fn foreach_modifiers() {
+        let command = parse(&["foreach", "--placeholder", "{}", "echo {#} {/.} {2}"]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, "a/b.txt c\nd e\n".as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "1 b c\n\n2 d e e\n\n");
+    }
+
+    #[test]
+    fn foreach_batch() {
+        let input = "a\nb\nc\n";
+        let command = parse(&["foreach", "--batch", "2", "--", "printf", "%s,"]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, input.as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "a,b,\nc,\n");
+
+        let command = parse(&["foreach", "--max-args", "2", "echo __var end"]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, input.as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "a b end\n\nc end\n\n");
+
+        let command = parse(&["foreach", "--max-chars", "100000000", "echo"]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, input.as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "a b c\n\n");
+    }
+
+    #[test]
+    fn filter() {
+        let input = "a\nbb\nc\n";
+        let command = parse(&["filter", "grep -q b"]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, input.as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "bb\n");
+
+        let command = parse(&["filter", "--invert", "test __var = a"]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, input.as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "bb\nc\n");
+
+        let command = parse(&["filter", "-0", "grep -q b"]);
 
-            let result = template(&input, &shell, &begin, &end, !raw_output);
+        let mut writer = TestWriter::new();
+        perform_command(command, "a\nb\0c\0".as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "a\nb\0");
+
+        let command = parse(&["filter", "--propagate-exit-code", "true"]);
+        let result = perform_command(command, input.as_bytes(), &mut TestWriter::new());
+        assert!(matches!(result, Err(Error::Usage(_))));
+
+        let command = parse(&["filter", "does-not-exist"]);
+        let result = perform_command(command, input.as_bytes(), &mut TestWriter::new());
+        assert!(matches!(result, Err(Error::Spawn { .. })));
+    }
+
+    #[test]
+    fn dry_run() {
+        let input = "a b\nit's\n";
+        let command = parse(&["map", "--dry-run", "tr a-z A-Z"]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, input.as_bytes(), &mut writer).unwrap();
+        assert_eq!(
+            writer,
+            "printf %s 'a b' | tr a-z A-Z\nprintf %s 'it'\\''s' | tr a-z A-Z\n"
+        );
+
+        let command = parse(&["foreach", "--dry-run", "--placeholder", "{}", "rm -- {}"]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, input.as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "rm -- 'a b'\nrm -- 'it'\\''s'\n");
+
+        let command = parse(&["foreach", "--dry-run", "--batch", "2", "rm"]);
+
+        let mut writer = TestWriter::new();
+        perform_command(command, input.as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "rm 'a b' 'it'\\''s'\n");
+
+        let command = parse(&["map", "--dry-run", "--coprocess", "cat"]);
 
-            writeln!(output, "{}", result)?;
+        let mut writer = TestWriter::new();
+        perform_command(command, input.as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "printf %s 'a b\nit'\\''s\n' | cat\n");
+
+        // files given for stderr and the trace are left alone
+        let path = std::env::temp_dir().join(format!("string-dry-run-{}", std::process::id()));
+        std::fs::write(&path, "keep").unwrap();
+        let stderr = format!("--stderr=file:{}", path.display());
+        let trace = format!("--trace={}", path.display());
+        for args in [
+            &["map", "--dry-run", &stderr, &trace, "cat"][..],
+            &["foreach", "--dry-run", &stderr, &trace, "cat"],
+        ] {
+            perform_command(parse(args), input.as_bytes(), &mut TestWriter::new()).unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep");
         }
-        Chars => {
-            for c in input.chars() {
-                writeln!(output, "{}", c)?;
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn keep_going() {
+        let input = "1\n2\n3\n4\n";
+        let command = parse(&[
+            "foreach",
+            "--keep-going",
+            "--failure-marker",
+            "FAILED",
+            "-j",
+            "2",
+            "sh -c 'test $((__var % 2)) = 0 && printf __var'",
+        ]);
+
+        let mut writer = TestWriter::new();
+        let error = perform_command(command, input.as_bytes(), &mut writer).unwrap_err();
+        assert_eq!(writer, "FAILED\n2\nFAILED\n4\n");
+
+        match error {
+            Error::Failures { failures, total } => {
+                assert_eq!(total, 4);
+                let origins: Vec<_> = failures.iter().map(|(o, _)| o.as_str()).collect();
+                assert_eq!(origins, ["line 1", "line 3"]);
             }
+            e => panic!("unexpected error {}", e),
         }
-        Map { command } => {
-            let shell: Vec<&str> = command.iter().map(|s| s.as_str()).collect();
+        assert!(matches!(
+            perform_command(
+                parse(&["map", "--keep-going", "false"]),
+                input.as_bytes(),
+                &mut TestWriter::new()
+            ),
+            Err(Error::Failures { .. })
+        ));
 
-            for line in input.lines() {
-                let result = execute(line, &shell);
-                writeln!(output, "{}", result)?;
-            }
+        // the marker only applies when keeping going
+        let args = ["string", "foreach", "--failure-marker", "X", "false"];
+        assert!(StringCommand::from_iter_safe(args).is_err());
+    }
+
+    #[test]
+    fn separators() {
+        let cases: &[(&[&str], &str, &str)] = &[
+            (&["reverse", "-0"], "a\nb\0c\0", "c\0a\nb\0"),
+            (
+                &["trim", "--null", "--output-separator", ","],
+                " a \0\0 b\0",
+                "a,b,",
+            ),
+            (
+                &["distinct", "-l", "--record-separator", ";"],
+                "a;b;a;c",
+                "a;b;c;",
+            ),
+            (&["distinct", "--output-separator", "|"], "a b a\n", "a|b|"),
+            (&["distinct", "-0"], "a b\0a\0", "a\0b\0"),
+            (
+                &["interleave", "2", "--record-separator", "::"],
+                "a::b::c::",
+                "a::c::",
+            ),
+            (&["map", "-0", "tr a-z A-Z"], "a\nb\0c\0", "A\nB\0C\0"),
+            (
+                &["foreach", "-0", "--batch", "2", "printf %s-"],
+                "a b\0c\0d",
+                "a b-c-\0d-\0",
+            ),
+        ];
+
+        for (args, input, expected) in cases {
+            let mut writer = TestWriter::new();
+            perform_command(parse(args), input.as_bytes(), &mut writer).unwrap();
+            assert_eq!(writer, *expected, "{:?}", args);
         }
-        Foreach { command } => {
-            for line in input.lines() {
-                let command: Vec<_> = command.iter().map(|s| s.replace("__var", line)).collect();
 
-                let result = execute_command(&command);
-                writeln!(output, "{result}")?;
+        assert!(
+            StringCommand::from_iter_safe(["string", "trim", "-0", "--record-separator", ","])
+                .is_err()
+        );
+        assert!(
+            StringCommand::from_iter_safe(["string", "trim", "--record-separator", ""]).is_err()
+        );
+    }
+
+    /// Input failing after `content` has been read
+    fn failing_input(content: &str) -> impl std::io::BufRead + '_ {
+        struct Failing;
+        impl std::io::Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("input broke"))
             }
         }
-    };
 
-    Ok(())
-}
+        std::io::BufReader::new(std::io::Read::chain(content.as_bytes(), Failing))
+    }
 
-fn pick_line(input: &str, number: usize) -> &str {
-    if let Some((_, line)) = input
-        .split('\n')
-        .enumerate()
-        .find(|(index, _)| *index == number)
-    {
-        line
-    } else {
-        eprintln!("input does not have enough lines");
-        std::process::exit(1);
+    #[test]
+    fn streaming() {
+        let input = " a\nb \n";
+        let cases: &[(&[&str], &str)] = &[
+            (&["trim"], "a\nb\n"),
+            (&["case", "upper"], " A\nB \n"),
+            (&["chars"], " \na\n\n\nb\n \n\n\n"),
+            (&["interleave", "2"], " a\n"),
+            (&["distinct", "-l"], " a\nb \n"),
+            (&["map", "cat"], " a\nb \n"),
+            (&["foreach", "echo __var"], " a\n\nb \n\n"),
+            (&["template"], " a\nb \n"),
+        ];
+
+        for (args, expected) in cases {
+            let mut writer = TestWriter::new();
+            let result = perform_command(parse(args), failing_input(input), &mut writer);
+
+            assert!(matches!(result, Err(Error::Io(_))), "{:?}", args);
+            assert_eq!(writer, *expected, "{:?}", args);
+        }
+
+        // lines after the picked one aren't read
+        let mut writer = TestWriter::new();
+        perform_command(parse(&["line", "1"]), failing_input("a\nb\n"), &mut writer).unwrap();
+        assert_eq!(writer, "b\n");
     }
-}
 
-fn substr(input: &str, start: usize, end: usize) -> String {
-    if start > end {
-        eprintln!("start value must be smaller than end value");
-        std::process::exit(1);
+    #[test]
+    fn line_after_last_newline() {
+        let mut writer = TestWriter::new();
+        perform_command(parse(&["line", "2"]), "a\nb\n".as_bytes(), &mut writer).unwrap();
+        assert_eq!(writer, "\n");
+
+        let result = perform_command(parse(&["line", "3"]), "a\nb\n".as_bytes(), &mut writer);
+        assert!(matches!(result, Err(Error::OutOfRange(_))));
     }
 
-    let amount = end - start;
+    #[test]
+    fn map_failure() {
+        let command = parse(&["map", "--", "sh", "-c", "exit 2"]);
 
-    input.chars().skip(start).take(amount).collect()
+        let mut writer = TestWriter::new();
+        let result = perform_command(command, "a\nb".as_bytes(), &mut writer);
+        assert!(matches!(result, Err(Error::Exit { .. })));
+    }
 }
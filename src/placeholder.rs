@@ -0,0 +1,410 @@
+//! Substituting lines of input into the arguments of commands
+//!
+//! A placeholder which can be split into two halves, like `{}`, may contain a modifier between them,
+//! picking a part of the line or information about it:
+//!
+//! | placeholder | substituted with                                   |
+//! |-------------|----------------------------------------------------|
+//! | `{}`        | the line                                           |
+//! | `{#}`       | number of the line, starting at 1                  |
+//! | `{i}`       | index of the line, starting at 0                   |
+//! | `{N}`       | field N of the line, starting at 1                 |
+//! | `{/}`       | basename of the line                               |
+//! | `{//}`      | dirname of the line                                |
+//! | `{.}`       | the line without extension                         |
+//! | `{/.}`      | basename of the line without extension             |
+//! | `{+.}`      | extension of the line including the dot            |
+
+use crate::error::{Error, Result};
+use std::borrow::Cow;
+
+#[derive(Debug, Clone)]
+pub struct Placeholder {
+    open: String,
+    /// empty for placeholders which can't have modifiers
+    close: String,
+    /// shell-escape substituted values in every argument, for commands whose arguments are shell scripts
+    quote: bool,
+    /// separator of fields, whitespace if `None`
+    field_separator: Option<String>,
+}
+
+/// A line to substitute into arguments
+#[derive(Debug, Clone, Copy)]
+pub struct Line<'a> {
+    pub text: &'a str,
+    /// position of the line in the input, starting at 0
+    pub index: usize,
+}
+
+/// What a placeholder gets substituted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part {
+    Line,
+    Number,
+    Index,
+    Field(usize),
+    Basename,
+    Dirname,
+    WithoutExtension,
+    BasenameWithoutExtension,
+    Extension,
+}
+
+impl Part {
+    fn parse(modifier: &str) -> Option<Part> {
+        Some(match modifier {
+            "" => Part::Line,
+            "#" => Part::Number,
+            "i" => Part::Index,
+            "/" => Part::Basename,
+            "//" => Part::Dirname,
+            "." => Part::WithoutExtension,
+            "/." => Part::BasenameWithoutExtension,
+            "+." => Part::Extension,
+            _ if modifier.bytes().all(|b| b.is_ascii_digit()) => match modifier.parse() {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => Part::Field(n),
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// A piece of an argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment<'s> {
+    Text(&'s str),
+    Placeholder(Part),
+}
+
+impl<'s> Segment<'s> {
+    fn text(&self) -> &'s str {
+        match self {
+            Segment::Text(text) => text,
+            Segment::Placeholder(_) => "",
+        }
+    }
+}
+
+impl Placeholder {
+    pub fn new(token: &str, quote: bool, field_separator: Option<&str>) -> Result<Self> {
+        if token.is_empty() {
+            return Err(Error::Usage("placeholder must not be empty".into()));
+        }
+        if field_separator == Some("") {
+            return Err(Error::Usage("field separator must not be empty".into()));
+        }
+
+        let chars = token.chars().count();
+        let (open, close) = if chars.is_multiple_of(2) {
+            let middle = token.char_indices().nth(chars / 2).map_or(0, |(i, _)| i);
+            token.split_at(middle)
+        } else {
+            (token, "")
+        };
+
+        Ok(Placeholder {
+            open: open.to_string(),
+            close: close.to_string(),
+            quote,
+            field_separator: field_separator.map(|s| s.to_string()),
+        })
+    }
+
+    /// Replace every placeholder in `arg` with the corresponding part of `line`.
+    /// A backslash right before a placeholder keeps the placeholder itself instead.
+    pub fn substitute(&self, arg: &str, line: Line) -> String {
+        self.segments(arg)
+            .into_iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.into(),
+                Segment::Placeholder(part) => self.quoted(self.value(part, line)),
+            })
+            .collect()
+    }
+
+    /// Whether `arg` contains a placeholder which isn't escaped
+    pub fn is_in(&self, arg: &str) -> bool {
+        self.segments(arg)
+            .iter()
+            .any(|s| matches!(s, Segment::Placeholder(_)))
+    }
+
+    /// Build the arguments of a command working on several lines at once.
+    ///
+    /// An argument which is a single placeholder becomes one argument per line,
+    /// without any such argument the lines are appended to the command.
+    /// Appended lines are quoted like substituted ones.
+    pub fn batch(&self, command: &[String], lines: &[Line]) -> Result<Vec<String>> {
+        let mut args = Vec::new();
+        let mut substituted = false;
+
+        for arg in command {
+            let segments = self.segments(arg);
+
+            match segments.as_slice() {
+                [Segment::Placeholder(part)] => {
+                    substituted = true;
+                    args.extend(
+                        lines
+                            .iter()
+                            .map(|&line| self.quoted(self.value(*part, line)).into_owned()),
+                    );
+                }
+                _ if segments.iter().all(|s| matches!(s, Segment::Text(_))) => {
+                    args.push(segments.iter().map(|s| s.text()).collect());
+                }
+                _ => {
+                    return Err(Error::Usage(format!(
+                        "placeholders must be whole arguments when running batches, not in `{}`",
+                        arg
+                    )))
+                }
+            }
+        }
+
+        if !substituted {
+            args.extend(
+                lines
+                    .iter()
+                    .map(|&line| self.quoted(self.value(Part::Line, line)).into_owned()),
+            );
+        }
+
+        Ok(args)
+    }
+
+    /// Split `arg` into text and placeholders
+    fn segments<'s>(&self, arg: &'s str) -> Vec<Segment<'s>> {
+        let mut segments = Vec::new();
+        let mut rest = arg;
+
+        while let Some(found) = rest.find(&self.open) {
+            let before = &rest[..found];
+            let after = &rest[found + self.open.len()..];
+
+            let parsed = after.find(&self.close).and_then(|end| {
+                let length = self.open.len() + end + self.close.len();
+                Part::parse(&after[..end]).map(|part| (part, length))
+            });
+            let (part, length) = match parsed {
+                Some(parsed) => parsed,
+                None => {
+                    // not a placeholder, keep looking after its opening
+                    segments.push(Segment::Text(&rest[..found + self.open.len()]));
+                    rest = after;
+                    continue;
+                }
+            };
+
+            let placeholder = &rest[found..found + length];
+            match before.strip_suffix('\\') {
+                Some(before) => {
+                    segments.push(Segment::Text(before));
+                    segments.push(Segment::Text(placeholder));
+                }
+                None => {
+                    segments.push(Segment::Text(before));
+                    segments.push(Segment::Placeholder(part));
+                }
+            }
+
+            rest = &rest[found + length..];
+        }
+
+        segments.push(Segment::Text(rest));
+        segments.retain(|s| !matches!(s, Segment::Text("")));
+        segments
+    }
+
+    fn value<'a>(&self, part: Part, line: Line<'a>) -> Cow<'a, str> {
+        let text = line.text;
+
+        match part {
+            Part::Line => text.into(),
+            Part::Number => (line.index + 1).to_string().into(),
+            Part::Index => line.index.to_string().into(),
+            Part::Field(n) => {
+                let field = match &self.field_separator {
+                    Some(separator) => text.split(separator.as_str()).nth(n - 1),
+                    None => text.split_whitespace().nth(n - 1),
+                };
+                // like awk, missing fields are empty
+                field.unwrap_or("").into()
+            }
+            Part::Basename => basename(text).into(),
+            Part::Dirname => match text.rfind('/') {
+                Some(0) => "/".into(),
+                Some(i) => text[..i].into(),
+                None => ".".into(),
+            },
+            Part::WithoutExtension => split_extension(text).0.into(),
+            Part::BasenameWithoutExtension => split_extension(basename(text)).0.into(),
+            Part::Extension => split_extension(text).1.into(),
+        }
+    }
+
+    fn quoted<'a>(&self, value: Cow<'a, str>) -> Cow<'a, str> {
+        if self.quote {
+            shell_words::quote(&value).into_owned().into()
+        } else {
+            value
+        }
+    }
+}
+
+fn basename(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Split a path in front of the dot of its extension, hidden files like `.profile` have no extension
+fn split_extension(path: &str) -> (&str, &str) {
+    let name = path.rfind('/').map_or(0, |i| i + 1);
+
+    match path[name..].rfind('.') {
+        Some(0) | None => (path, ""),
+        Some(dot) => path.split_at(name + dot),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn line(text: &str) -> Line<'_> {
+        Line { text, index: 4 }
+    }
+
+    #[test]
+    fn substitute() {
+        let placeholder = Placeholder::new("{}", false, None).unwrap();
+
+        assert_eq!(placeholder.substitute("{}", line("a")), "a");
+        assert_eq!(placeholder.substitute("x{}y{}", line("a")), "xaya");
+        assert_eq!(
+            placeholder.substitute("no placeholder", line("a")),
+            "no placeholder"
+        );
+        // lines containing the placeholder aren't substituted again
+        assert_eq!(placeholder.substitute("{}-{}", line("{}")), "{}-{}");
+    }
+
+    #[test]
+    fn escape() {
+        let placeholder = Placeholder::new("__var", false, None).unwrap();
+
+        assert_eq!(
+            placeholder.substitute(r"\__var=__var", line("a")),
+            "__var=a"
+        );
+        assert_eq!(placeholder.substitute(r"a\b", line("a")), r"a\b");
+
+        let placeholder = Placeholder::new("{}", false, None).unwrap();
+        assert_eq!(placeholder.substitute(r"\{/} {/}", line("a/b")), "{/} b");
+    }
+
+    #[test]
+    fn quote() {
+        let placeholder = Placeholder::new("{}", true, None).unwrap();
+
+        assert_eq!(
+            placeholder.substitute("echo {}", line("plain")),
+            "echo plain"
+        );
+        assert_eq!(
+            placeholder.substitute("echo {}", line("a'b; rm -rf /")),
+            r#"echo 'a'\''b; rm -rf /'"#
+        );
+        assert_eq!(
+            placeholder.substitute("echo {2}", line("a b;c")),
+            "echo 'b;c'"
+        );
+        // every argument is quoted, not only the ones a shell interprets
+        assert_eq!(placeholder.substitute("{}", line("a b")), "'a b'");
+    }
+
+    #[test]
+    fn modifiers() {
+        let placeholder = Placeholder::new("{}", false, None).unwrap();
+        let substitute = |arg, text| placeholder.substitute(arg, line(text));
+
+        assert_eq!(substitute("{#} {i}", "a"), "5 4");
+        assert_eq!(substitute("{2}|{1}|{3}", " a  b "), "b|a|");
+        assert_eq!(
+            substitute("{/}|{//}", "dir/sub/file.tar.gz"),
+            "file.tar.gz|dir/sub"
+        );
+        assert_eq!(
+            substitute("{.}|{/.}|{+.}", "dir/file.tar.gz"),
+            "dir/file.tar|file.tar|.gz"
+        );
+        assert_eq!(substitute("{/}|{//}|{.}|{+.}", "file"), "file|.|file|");
+        assert_eq!(substitute("{//}|{.}", "/.profile"), "/|/.profile");
+        assert_eq!(substitute("{.}", "dir.d/file"), "dir.d/file");
+        // not placeholders
+        assert_eq!(substitute("{0} {x} {", "a"), "{0} {x} {");
+        assert_eq!(substitute("awk '{print}' {}", "a"), "awk '{print}' a");
+    }
+
+    #[test]
+    fn field_separator() {
+        let placeholder = Placeholder::new("[[]]", false, Some(",")).unwrap();
+
+        assert_eq!(
+            placeholder.substitute("[[2]] [[1]]", line("a b,c")),
+            "c a b"
+        );
+    }
+
+    #[test]
+    fn is_in() {
+        let placeholder = Placeholder::new("{}", false, None).unwrap();
+
+        assert!(placeholder.is_in("test -f {}"));
+        assert!(placeholder.is_in("{/.}"));
+        assert!(!placeholder.is_in(r"echo \{}"));
+        assert!(!placeholder.is_in("{print}"));
+    }
+
+    #[test]
+    fn batch() {
+        let placeholder = Placeholder::new("{}", false, None).unwrap();
+        let lines = [line("a/x.txt"), line("b/y.txt")];
+        let command = |args: &[&str]| args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let args = placeholder.batch(&command(&["rm", "-f"]), &lines).unwrap();
+        assert_eq!(args, ["rm", "-f", "a/x.txt", "b/y.txt"]);
+
+        let args = placeholder
+            .batch(&command(&["cp", "{/}", r"\{}", "dir"]), &lines)
+            .unwrap();
+        assert_eq!(args, ["cp", "x.txt", "y.txt", "{}", "dir"]);
+
+        let quoting = Placeholder::new("{}", true, None).unwrap();
+        let lines = [line("a b"), line("c")];
+        let args = quoting
+            .batch(&command(&["sh", "-c", "{}"]), &lines)
+            .unwrap();
+        assert_eq!(args, ["sh", "-c", "'a b'", "c"]);
+        let args = quoting.batch(&command(&["echo"]), &lines).unwrap();
+        assert_eq!(args, ["echo", "'a b'", "c"]);
+
+        let error = placeholder
+            .batch(&command(&["echo", "-{}-"]), &lines)
+            .unwrap_err();
+        assert!(matches!(error, Error::Usage(_)));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(
+            Placeholder::new("", false, None),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(
+            Placeholder::new("{}", false, Some("")),
+            Err(Error::Usage(_))
+        ));
+    }
+}
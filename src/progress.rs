@@ -0,0 +1,127 @@
+//! Live progress of commands running for many lines, drawn on stderr
+
+use std::time::{Duration, Instant};
+
+/// How often the progress gets redrawn at most
+const INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Progress {
+    /// number of items, if known
+    total: Option<usize>,
+    done: usize,
+    start: Instant,
+    drawn: Option<Instant>,
+    /// items done when last drawn
+    shown: usize,
+}
+
+impl Progress {
+    pub fn new(total: Option<usize>) -> Self {
+        Progress {
+            total,
+            done: 0,
+            start: Instant::now(),
+            drawn: None,
+            shown: 0,
+        }
+    }
+
+    /// Count one more item as done
+    pub fn advance(&mut self) {
+        self.done += 1;
+
+        let due = self.drawn.is_none_or(|drawn| drawn.elapsed() >= INTERVAL);
+        if due || Some(self.done) == self.total {
+            self.draw();
+        }
+    }
+
+    fn draw(&mut self) {
+        // clear the rest of the previous line, it might have been longer
+        eprint!("\r{}\x1b[K", self.line(self.start.elapsed()));
+        self.drawn = Some(Instant::now());
+        self.shown = self.done;
+    }
+
+    /// e.g. `120/400 (30%) 12.0/s ETA 0:23`
+    fn line(&self, elapsed: Duration) -> String {
+        let rate = self.done as f64 / elapsed.as_secs_f64().max(0.001);
+
+        let total = match self.total {
+            Some(total) => total,
+            None => return format!("{} {:.1}/s", self.done, rate),
+        };
+
+        let percent = (self.done * 100).checked_div(total).unwrap_or(100);
+        let eta = if rate > 0.0 {
+            let remaining = total.saturating_sub(self.done) as f64 / rate;
+            format_duration(Duration::from_secs_f64(remaining))
+        } else {
+            "?".to_string()
+        };
+
+        format!(
+            "{}/{} ({}%) {:.1}/s ETA {}",
+            self.done, total, percent, rate, eta
+        )
+    }
+}
+
+impl Drop for Progress {
+    /// Leave the final state on its own line, before anything else is written to stderr
+    fn drop(&mut self) {
+        if self.drawn.is_some() {
+            if self.shown != self.done {
+                self.draw();
+            }
+            eprintln!();
+        }
+    }
+}
+
+/// `h:mm:ss`, or `m:ss` below an hour
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn progress(total: Option<usize>, done: usize) -> Progress {
+        Progress {
+            done,
+            ..Progress::new(total)
+        }
+    }
+
+    #[test]
+    fn line() {
+        let second = Duration::from_secs(1);
+
+        assert_eq!(
+            progress(Some(400), 120).line(10 * second),
+            "120/400 (30%) 12.0/s ETA 0:23"
+        );
+        assert_eq!(progress(Some(10), 0).line(second), "0/10 (0%) 0.0/s ETA ?");
+        assert_eq!(progress(Some(0), 0).line(second), "0/0 (100%) 0.0/s ETA ?");
+        assert_eq!(progress(None, 5).line(2 * second), "5 2.5/s");
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(Duration::from_secs(5)), "0:05");
+        assert_eq!(format_duration(Duration::from_secs(754)), "12:34");
+        assert_eq!(
+            format_duration(Duration::from_secs(3 * 3600 + 61)),
+            "3:01:01"
+        );
+    }
+}
@@ -1,61 +1,256 @@
-use crate::exec::execute;
+use crate::{
+    error::{Error, Result},
+    exec::{self, execute},
+    util,
+};
+use std::{
+    io::{BufRead, Write},
+    path::Path,
+};
 
 use nom::{
     bytes::complete::{tag, take_until},
     combinator::opt,
-    sequence::{preceded, terminated},
+    error::ErrorKind,
+    sequence::preceded,
     IResult,
 };
 
-pub fn template(input: &str, shell: &[&str], begin: &str, end: &str, trim: bool) -> String {
-    if shell.len() == 0 {
-        eprintln!("must specify a shell");
-        std::process::exit(1);
+/// Everything needed to find and execute the commands of a template
+pub struct Settings<'a> {
+    /// in which shell the commands should be piped
+    pub shell: &'a [&'a str],
+    /// delimiter indicating beginning of command
+    pub begin: &'a str,
+    /// delimiter indicating end of command
+    pub end: &'a str,
+    /// trim new lines and whitespace of the start and end of output
+    pub trim: bool,
+    /// how the commands get spawned
+    pub exec: exec::Options,
+    /// file the template was read from, if any
+    pub file: Option<&'a Path>,
+}
+
+/// Location of the next character in the template
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Position {
+    /// number of commands before this position
+    block: usize,
+    /// starting at 1
+    line: usize,
+    /// starting at 1
+    column: usize,
+}
+
+impl Position {
+    fn new() -> Self {
+        Position {
+            block: 0,
+            line: 1,
+            column: 1,
+        }
     }
-    // 1 split text content and commands
-    // 2 map commands to their execution output
-    // 3 join and return
 
-    // unwrapping is safe, this will always be Ok, rest will always be ""
-    // TODO test this
-    let ast = parse(input, begin, end);
+    fn advance(&mut self, text: &str) {
+        for c in text.chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+    }
+
+    fn origin(&self) -> exec::Origin {
+        exec::Origin::Block {
+            index: self.block,
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    /// Variables describing the command at this position, exported to its process
+    fn env(&self, file: Option<&Path>) -> Vec<(String, String)> {
+        let file = file.map_or("-".into(), |f| f.to_string_lossy());
+
+        vec![
+            ("STRING_TEMPLATE_FILE".into(), file.into_owned()),
+            ("STRING_BLOCK_INDEX".into(), self.block.to_string()),
+            ("STRING_BLOCK_LINE".into(), self.line.to_string()),
+            ("STRING_BLOCK_COLUMN".into(), self.column.to_string()),
+        ]
+    }
+}
+
+/// Render a template read from `input`, replacing every command with its output.
+///
+/// The template is parsed incrementally. Text and command output are written to `output`
+/// as soon as they are available, so large templates never have to be held in memory.
+pub fn template(
+    mut input: impl BufRead,
+    output: &mut impl Write,
+    settings: &Settings,
+) -> Result<()> {
+    if settings.shell.is_empty() {
+        return Err(Error::Usage("must specify a shell".into()));
+    }
+    if settings.begin.is_empty() || settings.end.is_empty() {
+        return Err(Error::Usage("delimiters must not be empty".into()));
+    }
 
     let mut buffer = String::with_capacity(256);
+    let mut position = Position::new();
+
+    loop {
+        let eof = util::read_line(&mut input, &mut buffer)? == 0;
 
-    for c in ast {
-        buffer.push_str(c.text);
-        if let Some(cmd) = c.command {
-            let output = execute(cmd, shell);
-            let output = if trim { output.trim() } else { &output };
-            buffer.push_str(output);
+        let consumed = render(&buffer, output, settings, &mut position, eof)?;
+        buffer.drain(..consumed);
+
+        if eof {
+            return Ok(());
         }
     }
+}
 
-    buffer
+/// Render all text and commands that are complete in `buffer`.
+/// Returns the number of bytes consumed, the rest needs more input.
+fn render(
+    buffer: &str,
+    output: &mut impl Write,
+    settings: &Settings,
+    position: &mut Position,
+    eof: bool,
+) -> Result<usize> {
+    let Settings {
+        shell,
+        begin,
+        end,
+        trim,
+        ..
+    } = *settings;
+    let mut input = buffer;
+
+    loop {
+        // unwrapping is safe, parsing a command never fails
+        let (rest, c) = command(input, begin, end).unwrap();
+
+        match c.command {
+            Some(cmd) => {
+                output.write_all(c.text.as_bytes())?;
+                position.advance(c.text);
+
+                let mut options = settings.exec.clone();
+                options.env.extend(position.env(settings.file));
+                options.origin = Some(position.origin());
+
+                position.advance(&input[c.text.len()..input.len() - rest.len()]);
+                position.block += 1;
+
+                let result = execute(cmd, shell, &options)?;
+                let result = if trim { trim_output(&result) } else { &result };
+                output.write_all(result)?;
+                output.flush()?;
+
+                input = rest;
+            }
+            // no beginning delimiter in sight, but the end of the text
+            // might be the start of one.
+            None if rest.is_empty() => {
+                let keep = if eof { 0 } else { partial_match(c.text, begin) };
+                let split = c.text.len() - keep;
+
+                output.write_all(&c.text.as_bytes()[..split])?;
+                position.advance(&c.text[..split]);
+                input = &c.text[split..];
+                break;
+            }
+            // the command is not terminated yet
+            None => {
+                output.write_all(c.text.as_bytes())?;
+                position.advance(c.text);
+
+                if eof {
+                    return Err(Error::Parse {
+                        line: position.line,
+                        column: position.column,
+                        message: format!("command is missing closing delimiter `{}`", end),
+                    });
+                }
+
+                input = rest;
+                break;
+            }
+        }
+    }
+
+    Ok(buffer.len() - input.len())
 }
 
-#[derive(PartialEq, Debug)]
-struct Content<'a> {
-    text: &'a str,
-    command: Option<&'a str>,
+/// Length of the longest end of `text` which is the start of `delimiter`, without being all of it
+fn partial_match(text: &str, delimiter: &str) -> usize {
+    (1..delimiter.len())
+        .rev()
+        .filter(|&length| delimiter.is_char_boundary(length))
+        .find(|&length| text.ends_with(&delimiter[..length]))
+        .unwrap_or(0)
 }
 
-fn parse<'a>(s: &'a str, begin: &str, end: &str) -> Vec<Content<'a>> {
-    // many1(|st| command(st, begin, end))(s)
-    let mut input = s;
-    let mut result = Vec::new();
-    loop {
-        let (rest, command) = command(input, begin, end).unwrap();
-        result.push(command);
+/// Trim whitespace of the start and end of program output.
+/// Output that isn't valid utf-8 is only trimmed of ascii whitespace.
+fn trim_output(output: &[u8]) -> &[u8] {
+    match std::str::from_utf8(output) {
+        Ok(s) => s.trim().as_bytes(),
+        Err(_) => output.trim_ascii(),
+    }
+}
 
-        if rest == "" {
-            break;
-        }
+/// Render the template twice and make sure both renders are identical,
+/// before writing the result to `output`.
+pub fn template_verified(
+    mut input: impl BufRead,
+    output: &mut impl Write,
+    settings: &Settings,
+) -> Result<()> {
+    let text = util::read_to_string(&mut input)?;
+
+    let mut first = Vec::new();
+    template(text.as_bytes(), &mut first, settings)?;
+    let mut second = Vec::new();
+    template(text.as_bytes(), &mut second, settings)?;
+
+    if first != second {
+        let first: Vec<_> = first.split(|&b| b == b'\n').collect();
+        let second: Vec<_> = second.split(|&b| b == b'\n').collect();
+        // renders with equal lines differ in their number of lines,
+        // the first line only one of them has is reported against its end
+        let number = first
+            .iter()
+            .zip(&second)
+            .position(|(a, b)| a != b)
+            .unwrap_or(first.len().min(second.len()));
+        let line = |render: &[&[u8]]| match render.get(number) {
+            Some(line) => String::from_utf8_lossy(line).into_owned(),
+            None => format!("(render ends after line {})", render.len()),
+        };
 
-        input = rest;
+        return Err(Error::NotReproducible {
+            line: number + 1,
+            first: line(&first),
+            second: line(&second),
+        });
     }
 
-    return result;
+    output.write_all(&first)?;
+    Ok(())
+}
+
+#[derive(PartialEq, Debug)]
+struct Content<'a> {
+    text: &'a str,
+    command: Option<&'a str>,
 }
 
 /// Parse the first command from a given text, returnes the rest of the text
@@ -92,19 +287,103 @@ fn command<'a>(s: &'a str, begin: &str, end: &str) -> IResult<&'a str, Content<'
         }
     };
 
-    let (rest, command) = opt(preceded(tag(begin), terminated(take_until(end), tag(end))))(rest)?;
+    let (rest, command) = opt(preceded(tag(begin), |s| body(s, begin, end)))(rest)?;
 
     Ok((rest, Content { text, command }))
 }
 
+/// Parse the body of a command up to and including its end delimiter.
+///
+/// Nested pairs of begin and end delimiters, quoted strings and characters escaped by a backslash
+/// are part of the command, so e.g. `{ jq '{a: {b: 1}}' }` is a single command.
+fn body<'a>(s: &'a str, begin: &str, end: &str) -> IResult<&'a str, &'a str> {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+
+    let mut index = 0;
+    while let Some(c) = s[index..].chars().next() {
+        let rest = &s[index..];
+
+        if escaped {
+            escaped = false;
+        } else if let Some(q) = quote {
+            if c == '\\' && q == '"' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+        } else if let Some(after) = rest.strip_prefix(end) {
+            if depth == 0 {
+                return Ok((after, &s[..index]));
+            }
+
+            depth -= 1;
+            index += end.len();
+            continue;
+        } else if rest.starts_with(begin) {
+            depth += 1;
+            index += begin.len();
+            continue;
+        } else if c == '\'' || c == '"' {
+            quote = Some(c);
+        } else if c == '\\' {
+            escaped = true;
+        }
+
+        index += c.len_utf8();
+    }
+
+    Err(nom::Err::Error(nom::error::Error::new(
+        s,
+        ErrorKind::TakeUntil,
+    )))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn parse<'a>(s: &'a str, begin: &str, end: &str) -> Vec<Content<'a>> {
+        // many1(|st| command(st, begin, end))(s)
+        let mut input = s;
+        let mut result = Vec::new();
+        loop {
+            let (rest, command) = command(input, begin, end).unwrap();
+            result.push(command);
+
+            if rest.is_empty() {
+                break;
+            }
+
+            input = rest;
+        }
+
+        result
+    }
+
+    fn settings<'a>(shell: &'a [&'a str], begin: &'a str, end: &'a str) -> Settings<'a> {
+        Settings {
+            shell,
+            begin,
+            end,
+            trim: true,
+            exec: exec::Options::default(),
+            file: None,
+        }
+    }
+
+    fn render_str(input: &str, shell: &[&str], begin: &str, end: &str) -> String {
+        let settings = settings(shell, begin, end);
+        let mut output = Vec::new();
+        template(input.as_bytes(), &mut output, &settings).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
     #[test]
     fn template1() {
         let input = "hello (echo world)";
-        let result = template(input, &["sh"], "(", ")", true);
+        let result = render_str(input, &["sh"], "(", ")");
         let expected = "hello world";
 
         assert_eq!(expected, result);
@@ -113,7 +392,7 @@ mod test {
     #[test]
     fn template2() {
         let input = "Hey (echo VSauce), (echo Michael) here!";
-        let result = template(input, &["sh"], "(", ")", true);
+        let result = render_str(input, &["sh"], "(", ")");
         let expected = "Hey VSauce, Michael here!";
 
         assert_eq!(expected, result);
@@ -122,7 +401,7 @@ mod test {
     #[test]
     fn template3() {
         let input = "Hey { echo VSauce }, { echo Michael } here!";
-        let result = template(input, &["sh"], "{", "}", true);
+        let result = render_str(input, &["sh"], "{", "}");
         let expected = "Hey VSauce, Michael here!";
 
         assert_eq!(expected, result);
@@ -131,12 +410,159 @@ mod test {
     #[test]
     fn template4() {
         let input = "complex calculation: ^console.log(14)^";
-        let result = template(input, &["node"], "^", "^", true);
+        let result = render_str(input, &["node"], "^", "^");
         let expected = "complex calculation: 14";
 
         assert_eq!(expected, result);
     }
 
+    #[test]
+    fn template_multiline() {
+        let input = "first line\n{{ echo one\necho two }}\nlast line\n";
+        let result = render_str(input, &["sh"], "{{", "}}");
+        let expected = "first line\none\ntwo\nlast line\n";
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn template_delimiter_across_lines() {
+        let input = "text {\n{ echo hi }} {{ echo there }}{\n";
+        let result = render_str(input, &["sh"], "{{", "}}");
+        let expected = "text {\n{ echo hi }} there{\n";
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn template_reproducible() {
+        let settings = Settings {
+            exec: exec::Options::default().reproducible().unwrap(),
+            ..settings(&["sh"], "{{", "}}")
+        };
+        let input = "{{ echo $LC_ALL $TZ }} {{ echo $SOURCE_DATE_EPOCH }}";
+
+        let mut output = Vec::new();
+        template_verified(input.as_bytes(), &mut output, &settings).unwrap();
+
+        let epoch = std::env::var("SOURCE_DATE_EPOCH").unwrap_or("0".into());
+        assert_eq!(output, format!("C UTC {}", epoch).as_bytes());
+    }
+
+    #[test]
+    fn template_empty_delimiter() {
+        for (begin, end) in [("", "}}"), ("{{", "")] {
+            let mut output = Vec::new();
+            let result = template(
+                "a }} b".as_bytes(),
+                &mut output,
+                &settings(&["sh"], begin, end),
+            );
+            assert!(matches!(result, Err(Error::Usage(_))));
+        }
+    }
+
+    #[test]
+    fn partial_delimiter() {
+        assert_eq!(partial_match("text\n", "{{"), 0);
+        assert_eq!(partial_match("text {", "{{"), 1);
+        assert_eq!(partial_match("text {{", "{{"), 1);
+        assert_eq!(partial_match("a <%", "<%="), 2);
+        assert_eq!(partial_match("a ä", "äö"), 2);
+    }
+
+    #[test]
+    fn template_not_reproducible() {
+        // every render has one line more than the one before
+        let path = std::env::temp_dir().join(format!("string-renders-{}", std::process::id()));
+        let input = format!("{{{{ echo x >> {0}; cat {0} }}}}", path.display());
+
+        let mut output = Vec::new();
+        let settings = settings(&["sh"], "{{", "}}");
+        let result = template_verified(input.as_bytes(), &mut output, &settings);
+        std::fs::remove_file(path).unwrap();
+
+        match result {
+            Err(Error::NotReproducible {
+                line,
+                first,
+                second,
+            }) => {
+                assert_eq!(line, 2);
+                assert_eq!(first, "(render ends after line 1)");
+                assert_eq!(second, "x");
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn template_block_env() {
+        let input = "{{ echo $STRING_TEMPLATE_FILE $STRING_BLOCK_INDEX }}\n\
+                     äö {{ echo $STRING_BLOCK_LINE:$STRING_BLOCK_COLUMN }}\n\
+                     {{ echo $STRING_BLOCK_INDEX }}";
+        let result = render_str(input, &["sh"], "{{", "}}");
+        let expected = "- 0\näö 2:4\n2";
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn template_unterminated() {
+        let settings = settings(&["sh"], "{{", "}}");
+        let input = "hello\n  {{ echo world }";
+
+        let mut output = Vec::new();
+        let result = template(input.as_bytes(), &mut output, &settings);
+
+        assert!(matches!(
+            result,
+            Err(Error::Parse {
+                line: 2,
+                column: 3,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn template_failing_command() {
+        let settings = settings(&["sh"], "{{", "}}");
+        let input = "before {{ exit 1 }} after";
+
+        let mut output = Vec::new();
+        let result = template(input.as_bytes(), &mut output, &settings);
+
+        assert!(matches!(result, Err(Error::Exit { .. })));
+        assert_eq!(output, b"before ");
+    }
+
+    #[test]
+    fn template_raw_output() {
+        let settings = Settings {
+            exec: exec::Options {
+                decoding: exec::Decoding::Raw,
+                ..exec::Options::default()
+            },
+            ..settings(&["sh"], "{{", "}}")
+        };
+        let input = "latin1: {{ printf ' \\374\\n' }}!";
+
+        let mut output = Vec::new();
+        template(input.as_bytes(), &mut output, &settings).unwrap();
+
+        assert_eq!(output, b"latin1: \xfc!");
+    }
+
+    #[test]
+    fn position() {
+        let mut position = Position::new();
+        position.advance("ab\ncdé");
+
+        assert_eq!(position.line, 2);
+        assert_eq!(position.column, 4);
+    }
+
     #[test]
     fn parse_empty() {
         let res = parse("", "{", "}");
@@ -266,4 +692,68 @@ mod test {
         assert_eq!(res.0, "");
         assert_eq!(res.1, content);
     }
+
+    fn body_of(input: &str, begin: &str, end: &str) -> Option<String> {
+        let (_, content) = command(input, begin, end).unwrap();
+        content.command.map(str::to_string)
+    }
+
+    #[test]
+    fn parsing_nested() {
+        let cases = [
+            ("a {jq '{a: {b: 1}}'} b", "jq '{a: {b: 1}}'"),
+            ("{echo ${x}}", "echo ${x}"),
+            ("{echo {a,b}{c,d}} rest}", "echo {a,b}{c,d}"),
+            ("{ echo \\} }", " echo \\} "),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(
+                body_of(input, "{", "}"),
+                Some(expected.to_string()),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn parsing_quoted() {
+        let cases = [
+            ("{{ echo \"}}\" }}", " echo \"}}\" "),
+            ("{{ echo '}}' }}", " echo '}}' "),
+            ("{{ echo \"a\\\"}}\" }}", " echo \"a\\\"}}\" "),
+            ("{{ printf '%s' \"it's\" }}", " printf '%s' \"it's\" "),
+            (
+                "{{ jq '{a: {b: 1}}' < x.json }}",
+                " jq '{a: {b: 1}}' < x.json ",
+            ),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(
+                body_of(input, "{{", "}}"),
+                Some(expected.to_string()),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn parsing_same_delimiters() {
+        assert_eq!(
+            body_of("^console.log('^')^ ^", "^", "^"),
+            Some("console.log('^')".to_string())
+        );
+        assert_eq!(body_of("$echo $HOME$", "$", "$"), Some("echo ".to_string()));
+    }
+
+    #[test]
+    fn parsing_unterminated() {
+        assert_eq!(body_of("{{ echo '}}", "{{", "}}"), None);
+        assert_eq!(body_of("{ echo {x}", "{", "}"), None);
+        // a lone apostrophe starts a quote, even in a comment
+        assert_eq!(body_of("{{ echo hi # don't }}", "{{", "}}"), None);
+    }
 }
@@ -0,0 +1,199 @@
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::Write,
+    path::Path,
+    process::ExitStatus,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use crate::exec::Origin;
+
+/// Records every spawned process as a line of JSON
+#[derive(Debug, Clone)]
+pub struct Trace {
+    file: Arc<Mutex<File>>,
+}
+
+/// A single invocation of a process
+#[derive(Debug)]
+pub struct Record<'a> {
+    /// the command piped into the shell or the program with its arguments
+    pub command: &'a str,
+    pub shell: Option<&'a [&'a str]>,
+    pub origin: Option<Origin>,
+    /// starting at 1, increases with every retry
+    pub attempt: u32,
+    pub start: SystemTime,
+    pub duration: Duration,
+    /// `None` if the process couldn't be started or got killed
+    pub status: Option<ExitStatus>,
+    /// bytes of output
+    pub stdout: Option<usize>,
+    /// bytes of stderr, if it went through `string`
+    pub stderr: Option<usize>,
+    /// why the invocation failed, other than an unsuccessful exit status
+    pub error: Option<String>,
+}
+
+impl Trace {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(Trace {
+            file: Arc::new(Mutex::new(File::create(path)?)),
+        })
+    }
+
+    pub fn record(&self, record: &Record) -> std::io::Result<()> {
+        let line = record.to_json();
+
+        // a poisoned lock still holds a perfectly fine file
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(line.as_bytes())
+    }
+}
+
+impl Record<'_> {
+    fn to_json(&self) -> String {
+        let mut json = String::with_capacity(256);
+
+        json.push_str("{\"command\":");
+        push_str(&mut json, self.command);
+
+        json.push_str(",\"shell\":");
+        match self.shell {
+            Some(shell) => {
+                json.push('[');
+                for (i, word) in shell.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    push_str(&mut json, word);
+                }
+                json.push(']');
+            }
+            None => json.push_str("null"),
+        }
+
+        json.push_str(",\"origin\":");
+        match self.origin {
+            Some(Origin::Block {
+                index,
+                line,
+                column,
+            }) => write!(
+                json,
+                "{{\"block\":{},\"line\":{},\"column\":{}}}",
+                index, line, column
+            )
+            .unwrap(),
+            Some(Origin::Line(line)) => write!(json, "{{\"line\":{}}}", line).unwrap(),
+            Some(Origin::Lines { first, last }) => {
+                write!(json, "{{\"first_line\":{},\"last_line\":{}}}", first, last).unwrap()
+            }
+            None => json.push_str("null"),
+        }
+
+        let start = self
+            .start
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        write!(
+            json,
+            ",\"attempt\":{},\"start\":{:.3},\"duration\":{:.3}",
+            self.attempt,
+            start.as_secs_f64(),
+            self.duration.as_secs_f64()
+        )
+        .unwrap();
+
+        json.push_str(",\"status\":");
+        push_option(&mut json, self.status.and_then(|s| s.code()));
+
+        #[cfg(unix)]
+        let signal = {
+            use std::os::unix::process::ExitStatusExt;
+            self.status.and_then(|s| s.signal())
+        };
+        #[cfg(not(unix))]
+        let signal: Option<i32> = None;
+        json.push_str(",\"signal\":");
+        push_option(&mut json, signal);
+
+        json.push_str(",\"stdout_bytes\":");
+        push_option(&mut json, self.stdout);
+        json.push_str(",\"stderr_bytes\":");
+        push_option(&mut json, self.stderr);
+
+        json.push_str(",\"error\":");
+        match &self.error {
+            Some(error) => push_str(&mut json, error),
+            None => json.push_str("null"),
+        }
+
+        json.push_str("}\n");
+        json
+    }
+}
+
+fn push_option(json: &mut String, value: Option<impl std::fmt::Display>) {
+    match value {
+        Some(value) => write!(json, "{}", value).unwrap(),
+        None => json.push_str("null"),
+    }
+}
+
+/// Append `s` as a quoted JSON string
+fn push_str(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn json() {
+        let record = Record {
+            command: "echo \"hi\"\n",
+            shell: Some(&["sh", "-e"]),
+            origin: Some(Origin::Line(3)),
+            attempt: 1,
+            start: SystemTime::UNIX_EPOCH + Duration::from_millis(1500),
+            duration: Duration::from_millis(20),
+            status: None,
+            stdout: Some(3),
+            stderr: None,
+            error: Some("timed\tout".into()),
+        };
+
+        let expected = concat!(
+            r#"{"command":"echo \"hi\"\n","shell":["sh","-e"],"origin":{"line":3},"#,
+            r#""attempt":1,"start":1.500,"duration":0.020,"status":null,"signal":null,"#,
+            r#""stdout_bytes":3,"stderr_bytes":null,"error":"timed\tout"}"#,
+            "\n"
+        );
+
+        assert_eq!(record.to_json(), expected);
+    }
+
+    #[test]
+    fn escaping() {
+        let mut json = String::new();
+        push_str(&mut json, "a\\b\u{1}ö");
+
+        assert_eq!(json, r#""a\\b\u0001ö""#);
+    }
+}
@@ -1,9 +1,206 @@
-use std::io::Read;
+use std::io::{BufRead, ErrorKind, Read};
 
-pub fn stdin_as_string() -> String {
+use crate::error::{Error, Result};
+
+pub fn read_to_string(mut input: impl Read) -> Result<String> {
     let mut buffer = String::new();
-    std::io::stdin()
-        .read_to_string(&mut buffer)
-        .expect("failed to read stdin to string.");
-    buffer
+    input.read_to_string(&mut buffer).map_err(invalid_input)?;
+    Ok(buffer)
+}
+
+/// Append the next line of `input` to `buffer`, returns the number of bytes read
+pub fn read_line(input: &mut impl BufRead, buffer: &mut String) -> Result<usize> {
+    input.read_line(buffer).map_err(invalid_input)
+}
+
+/// Records of `input` separated by `separator`, read one at a time like `str::split_terminator` splits them.
+/// Newline separated records may end with `\r\n`, like `str::lines` allows.
+///
+/// Iteration stops at the first error, which [`Records::finish`] returns.
+pub struct Records<'s, R> {
+    input: R,
+    separator: &'s str,
+    error: Option<Error>,
+}
+
+impl<'s, R: BufRead> Records<'s, R> {
+    /// `separator` must not be empty
+    pub fn new(input: R, separator: &'s str) -> Self {
+        Records {
+            input,
+            separator,
+            error: None,
+        }
+    }
+
+    /// The error reading the records, if any
+    pub fn finish(self) -> Result<()> {
+        self.error.map_or(Ok(()), Err)
+    }
+
+    fn read(&mut self) -> Result<Option<String>> {
+        let separator = self.separator.as_bytes();
+        let last = separator[separator.len() - 1];
+        let mut record = Vec::new();
+
+        loop {
+            if self.input.read_until(last, &mut record)? == 0 {
+                break;
+            }
+            if record.ends_with(separator) {
+                record.truncate(record.len() - separator.len());
+                if self.separator == "\n" && record.last() == Some(&b'\r') {
+                    record.pop();
+                }
+                return String::from_utf8(record)
+                    .map(Some)
+                    .map_err(|_| invalid_utf8());
+            }
+            // the end of the input
+            if record.last() != Some(&last) {
+                break;
+            }
+        }
+
+        if record.is_empty() {
+            return Ok(None);
+        }
+        String::from_utf8(record)
+            .map(Some)
+            .map_err(|_| invalid_utf8())
+    }
+}
+
+impl<R: BufRead> Iterator for Records<'_, R> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        if self.error.is_some() {
+            return None;
+        }
+
+        self.read().unwrap_or_else(|e| {
+            self.error = Some(e);
+            None
+        })
+    }
+}
+
+/// Split `s` into words, following the quoting rules of a POSIX shell
+pub fn shell_words(s: &str) -> Result<Vec<String>> {
+    shell_words::split(s)
+        .map_err(|e| Error::Usage(format!("can't split `{}` into words: {}", s, e)))
+}
+
+/// A command given as a single argument gets split into words, like a POSIX shell would.
+/// Commands given as several arguments are taken as they are.
+pub fn command_words(command: Vec<String>) -> Result<Vec<String>> {
+    match command.as_slice() {
+        [single] => shell_words(single),
+        _ => Ok(command),
+    }
+}
+
+/// Parse a number of bytes with an optional binary suffix, e.g. `512M` or `2G`
+pub fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let (number, factor) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        Some((i, 'T' | 't')) => (&s[..i], 1 << 40),
+        _ => (s, 1),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(factor))
+        .ok_or_else(|| format!("invalid size `{}`, expected e.g. 4096, 512K, 64M or 2G", s))
+}
+
+/// Parse a separator of records, which must not be empty
+pub fn parse_separator(s: &str) -> std::result::Result<String, String> {
+    if s.is_empty() {
+        return Err("separator must not be empty".into());
+    }
+    Ok(s.to_string())
+}
+
+fn invalid_input(e: std::io::Error) -> Error {
+    if e.kind() == ErrorKind::InvalidData {
+        invalid_utf8()
+    } else {
+        Error::Io(e)
+    }
+}
+
+fn invalid_utf8() -> Error {
+    Error::InvalidUtf8 {
+        what: "input".into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split_shell() {
+        let result = shell_words("bash -euo pipefail").unwrap();
+        assert_eq!(result, ["bash", "-euo", "pipefail"]);
+
+        let result = shell_words(r#"node -e 'console.log("a b")'"#).unwrap();
+        assert_eq!(result, ["node", "-e", r#"console.log("a b")"#]);
+
+        assert!(matches!(shell_words("echo 'open"), Err(Error::Usage(_))));
+    }
+
+    #[test]
+    fn records() {
+        let inputs = [
+            "", "a", "a\n", "a\r\nb\r", "\n\nb\n", "a::b:c::", "x\0y\0\0", "::",
+        ];
+
+        for input in inputs {
+            for separator in ["\n", "::", "\0"] {
+                let mut records = Records::new(input.as_bytes(), separator);
+                let read: Vec<_> = (&mut records).collect();
+                records.finish().unwrap();
+
+                let expected: Vec<_> = match separator {
+                    "\n" => input.lines().collect(),
+                    _ => input.split_terminator(separator).collect(),
+                };
+                assert_eq!(read, expected, "{:?} separated by {:?}", input, separator);
+            }
+        }
+    }
+
+    #[test]
+    fn records_invalid_utf8() {
+        let mut records = Records::new(&b"a\n\xff\nb\n"[..], "\n");
+
+        assert_eq!(records.next().as_deref(), Some("a"));
+        assert_eq!(records.next(), None);
+        assert!(matches!(records.finish(), Err(Error::InvalidUtf8 { .. })));
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("64m"), Ok(64 * 1024 * 1024));
+        assert_eq!(parse_size("2G"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("12X").is_err());
+    }
+
+    #[test]
+    fn split_command() {
+        let single = vec!["grep -v 'a b'".to_string()];
+        assert_eq!(command_words(single).unwrap(), ["grep", "-v", "a b"]);
+
+        let several = vec!["echo".to_string(), "a b".to_string()];
+        assert_eq!(command_words(several).unwrap(), ["echo", "a b"]);
+    }
 }
@@ -0,0 +1,76 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+/// Run `string` with the given arguments and input, returns its exit code
+fn exit_code(args: &[&str], input: &[u8]) -> i32 {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_string"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait().unwrap().code().unwrap()
+}
+
+#[test]
+fn success() {
+    assert_eq!(exit_code(&["trim"], b" hello "), 0);
+    assert_eq!(exit_code(&["--help"], b""), 0);
+    assert_eq!(exit_code(&["map", "cat"], b"a\nb"), 0);
+}
+
+#[test]
+fn usage_error() {
+    assert_eq!(exit_code(&["no-such-command"], b""), 2);
+    assert_eq!(exit_code(&["substr", "3", "1"], b"hello"), 2);
+    assert_eq!(exit_code(&["map", "--timeout", "-1", "cat"], b"a"), 2);
+    assert_eq!(exit_code(&["map", "--timeout", "1e300", "cat"], b"a"), 2);
+    assert_eq!(
+        exit_code(&["map", "--retry-delay", "1e300", "cat"], b"a"),
+        2
+    );
+}
+
+#[test]
+fn input_error() {
+    assert_eq!(exit_code(&["line", "5"], b"a\nb"), 3);
+    assert_eq!(exit_code(&["trim"], b"\xff\xfe"), 3);
+}
+
+#[test]
+fn execution_failure() {
+    assert_eq!(exit_code(&["map", "false"], b"a"), 4);
+    assert_eq!(
+        exit_code(&["foreach", "this-program-does-not-exist"], b"a"),
+        4
+    );
+    assert_eq!(exit_code(&["template"], b"{{ exit 9 }}"), 4);
+}
+
+#[test]
+fn parse_error() {
+    assert_eq!(exit_code(&["template"], b"{{ echo unterminated"), 5);
+}
+
+#[test]
+fn timeout() {
+    assert_eq!(exit_code(&["map", "--timeout", "0.1", "sh"], b"sleep 5"), 6);
+}
+
+#[test]
+fn propagated_exit_code() {
+    let args = ["map", "--propagate-exit-code", "--", "sh", "-c", "exit 7"];
+    assert_eq!(exit_code(&args, b"a"), 7);
+
+    let args = ["template", "--propagate-exit-code"];
+    assert_eq!(exit_code(&args, b"{{ exit 9 }}"), 9);
+
+    // errors not caused by a command keep their own exit code
+    let args = ["template", "--propagate-exit-code"];
+    assert_eq!(exit_code(&args, b"{{ unterminated"), 5);
+}